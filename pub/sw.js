@@ -0,0 +1,71 @@
+// Keeps the app shell and the last copy of each page around so links opens
+// offline, and always goes to the network first for pages so the list is
+// never stale.
+const CACHE = "links-v2";
+const SHELL = [
+  "/pub/tailwind.css",
+  "/pub/htmx.org@1.9.5.js",
+  "/pub/json-enc.js",
+  "/pub/icon.svg",
+];
+
+self.addEventListener("install", (event) => {
+  event.waitUntil(caches.open(CACHE).then((cache) => cache.addAll(SHELL)));
+  self.skipWaiting();
+});
+
+self.addEventListener("activate", (event) => {
+  event.waitUntil(
+    caches
+      .keys()
+      .then((keys) => Promise.all(keys.filter((key) => key !== CACHE).map((key) => caches.delete(key))))
+  );
+  self.clients.claim();
+});
+
+self.addEventListener("fetch", (event) => {
+  const request = event.request;
+  const url = new URL(request.url);
+  if (request.method === "POST" && url.origin === location.origin && url.pathname === "/logout") {
+    // saved pages belong to whoever was logged in
+    event.waitUntil(forgetPages());
+    return;
+  }
+  if (request.method !== "GET") {
+    return;
+  }
+  if (url.origin === location.origin && url.pathname.startsWith("/pub/")) {
+    event.respondWith(caches.match(request).then((cached) => cached || fetch(request)));
+    return;
+  }
+  if (request.mode === "navigate") {
+    event.respondWith(navigate(event, request));
+  }
+});
+
+// Pages come from the network when it's there, and the copy saved the last
+// time they loaded when it isn't. Redirects and errors aren't saved, so
+// being logged out never replaces a good page.
+async function navigate(event, request) {
+  try {
+    const response = await fetch(request);
+    if (response.ok && response.type === "basic") {
+      const copy = response.clone();
+      event.waitUntil(caches.open(CACHE).then((cache) => cache.put(request, copy)));
+    }
+    return response;
+  } catch (err) {
+    const cached = (await caches.match(request)) || (await caches.match("/"));
+    if (cached) {
+      return cached;
+    }
+    throw err;
+  }
+}
+
+async function forgetPages() {
+  const cache = await caches.open(CACHE);
+  const requests = await cache.keys();
+  const shell = SHELL.map((path) => new URL(path, location.origin).href);
+  await Promise.all(requests.filter((request) => !shell.includes(request.url)).map((request) => cache.delete(request)));
+}
@@ -0,0 +1,132 @@
+//! Versioned json api over links. Errors come back as json through the
+//! `error_pages` middleware since api clients send `Accept: application/json`.
+
+use axum::{
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+
+use crate::{
+    date, non_empty, tag_names, validate_url, Context, Error, Link, LinkJson, Page, PageParams,
+    Res, Status,
+};
+
+#[derive(Clone)]
+pub enum ApiRoute {
+    Links,
+    Link,
+}
+
+impl From<ApiRoute> for &'static str {
+    fn from(value: ApiRoute) -> Self {
+        match value {
+            ApiRoute::Links => "/api/v1/links",
+            ApiRoute::Link => "/api/v1/links/:id",
+        }
+    }
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route(ApiRoute::Links.into(), get(list).post(create))
+        .route(
+            ApiRoute::Link.into(),
+            get(show).put(update).patch(update).delete(delete),
+        )
+}
+
+async fn list(cx: Context, Query(cursor): Query<PageParams>) -> Res<Json<Page<LinkJson>>> {
+    let page = cx.links(&cursor).await?;
+    Ok(Json(cx.json_page(page).await?))
+}
+
+async fn show(cx: Context, Path(id): Path<String>) -> Res<Json<LinkJson>> {
+    let link = cx.link(&id).await?;
+    Ok(Json(cx.json_link(link).await?))
+}
+
+#[derive(Deserialize)]
+struct CreateParams {
+    url: String,
+    title: Option<String>,
+    notes: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+async fn create(cx: Context, Json(params): Json<CreateParams>) -> Res<Response> {
+    let url = params.url.trim().to_owned();
+    validate_url(&url)?;
+    if let Some(existing) = cx.link_by_url(&url).await? {
+        return Err(Error::Conflict(format!(
+            "{} is already saved on {}",
+            existing.url,
+            date(existing.created_at)
+        )));
+    }
+    let mut link = Link::new(&cx.user.id, url);
+    link.title = params.title.and_then(non_empty);
+    link.notes = params.notes.and_then(non_empty);
+    let link = cx
+        .create_link(link, &tag_names(&params.tags.join(",")))
+        .await?;
+    let location = format!("{}/{}", <&str>::from(ApiRoute::Links), link.id);
+    let link = cx.json_link(link).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(link),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct UpdateParams {
+    url: Option<String>,
+    title: Option<String>,
+    notes: Option<String>,
+    tags: Option<Vec<String>>,
+    status: Option<Status>,
+}
+
+async fn update(
+    cx: Context,
+    Path(id): Path<String>,
+    Json(params): Json<UpdateParams>,
+) -> Res<Json<LinkJson>> {
+    let mut link = cx.link(&id).await?;
+    if let Some(url) = params.url {
+        let url = url.trim().to_owned();
+        validate_url(&url)?;
+        if let Some(existing) = cx.link_by_url(&url).await? {
+            if existing.id != link.id {
+                return Err(Error::Conflict(format!("{} is already saved", url)));
+            }
+        }
+        link.set_url(&url);
+    }
+    if let Some(title) = params.title {
+        link.title = non_empty(title);
+    }
+    if let Some(notes) = params.notes {
+        link.notes = non_empty(notes);
+    }
+    cx.save_link(&link).await?;
+    if let Some(status) = params.status {
+        link = cx.set_status(link, status).await?;
+    }
+    if let Some(tags) = params.tags {
+        cx.set_tags(&link.id, &tag_names(&tags.join(","))).await?;
+    }
+    Ok(Json(cx.json_link(link).await?))
+}
+
+async fn delete(cx: Context, Path(id): Path<String>) -> Res<impl IntoResponse> {
+    let link = cx.link(&id).await?;
+    cx.delete_link(&link.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,901 @@
+//! Offline copies of saved pages. A page is fetched along with its
+//! stylesheets, images and fonts, which are inlined as data urls so the
+//! snapshot renders without touching the network.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+};
+
+use axum::{
+    body::{boxed, Body},
+    extract::Path,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use lol_html::{
+    element, html_content::ContentType, rewrite_str, text, ElementContentHandlers,
+    RewriteStrSettings, Selector,
+};
+use maud::html;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{now, warc::Warc, Context, Error, Fetch, Fetched, Res};
+
+/// Stop fetching resources once a capture adds up to this many bytes.
+const MAX_CAPTURE_BYTES: usize = 25 * 1024 * 1024;
+
+/// Stylesheets that `@import` other stylesheets are followed this deep.
+const MAX_IMPORT_DEPTH: usize = 3;
+
+/// Snapshots are served from our own origin, so nothing in them may run
+/// scripts or reach out to the network.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; img-src data:; style-src 'unsafe-inline' data:; font-src data:; media-src data:; sandbox allow-popups allow-popups-to-escape-sandbox";
+
+#[derive(Clone)]
+pub enum ArchiveRoute {
+    Snapshot,
+    Warc,
+    Export,
+}
+
+impl std::fmt::Display for ArchiveRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let x: &str = self.to_owned().into();
+        f.write_str(x)
+    }
+}
+
+impl From<ArchiveRoute> for &'static str {
+    fn from(value: ArchiveRoute) -> Self {
+        match value {
+            ArchiveRoute::Snapshot => "/links/:id/archive",
+            ArchiveRoute::Warc => "/links/:id/archive.warc.gz",
+            ArchiveRoute::Export => "/export.warc.gz",
+        }
+    }
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route(ArchiveRoute::Snapshot.into(), get(snapshot))
+        .route(ArchiveRoute::Warc.into(), get(warc))
+        .route(ArchiveRoute::Export.into(), get(export))
+}
+
+pub fn snapshot_url(id: &str) -> String {
+    let route: &str = ArchiveRoute::Snapshot.into();
+    route.replace(":id", id)
+}
+
+pub fn warc_url(id: &str) -> String {
+    let route: &str = ArchiveRoute::Warc.into();
+    route.replace(":id", id)
+}
+
+async fn snapshot(cx: Context, Path(id): Path<String>) -> Res<Response> {
+    let link = cx.link(&id).await?;
+    let snapshot = cx.snapshot(&link.id).await?.ok_or(Error::NotFound)?;
+    let html = with_banner(&snapshot.html()?, &link.url, snapshot.captured_at)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY),
+        ],
+        html,
+    )
+        .into_response())
+}
+
+async fn warc(cx: Context, Path(id): Path<String>) -> Res<Response> {
+    let link = cx.link(&id).await?;
+    let exchanges = cx.exchanges(&link.id).await?;
+    if exchanges.is_empty() {
+        return Err(Error::NotFound);
+    }
+    warc_response(&format!("{}.warc.gz", link.id), exchanges)
+}
+
+/// Streams every capture as one WARC file, a link at a time, so only one
+/// link's exchanges are in memory at once.
+async fn export(cx: Context) -> Res<Response> {
+    let filename = "links.warc.gz";
+    let link_ids = cx.captured_link_ids().await?;
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let result: Res<()> = async {
+            let mut warc = Warc::default();
+            warc.warcinfo(filename, now())?;
+            sender
+                .send_data(warc.finish().into())
+                .await
+                .map_err(|err| Error::Internal(err.to_string()))?;
+            for link_id in link_ids {
+                let mut warc = Warc::default();
+                for exchange in cx.exchanges(&link_id).await? {
+                    warc.add(&exchange)?;
+                }
+                sender
+                    .send_data(warc.finish().into())
+                    .await
+                    .map_err(|err| Error::Internal(err.to_string()))?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            log::warn!("warc export stopped: {:?}", err);
+            sender.abort();
+        }
+    });
+
+    Ok((warc_headers(filename), boxed(body)).into_response())
+}
+
+fn warc_response(filename: &str, exchanges: Vec<Exchange>) -> Res<Response> {
+    let mut warc = Warc::default();
+    warc.warcinfo(filename, now())?;
+    for exchange in exchanges {
+        warc.add(&exchange)?;
+    }
+
+    Ok((warc_headers(filename), warc.finish()).into_response())
+}
+
+fn warc_headers(filename: &str) -> [(header::HeaderName, String); 2] {
+    [
+        (header::CONTENT_TYPE, "application/gzip".to_owned()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        ),
+    ]
+}
+
+impl Warc {
+    fn add(&mut self, exchange: &Exchange) -> Res<()> {
+        let key = format!(
+            "{}:{}:{}",
+            exchange.link_id, exchange.captured_at, exchange.position
+        );
+        self.exchange(&key, exchange.captured_at, &exchange.fetched()?)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Snapshot {
+    /// The self-contained page, gzipped.
+    pub html: Vec<u8>,
+    pub captured_at: f64,
+}
+
+impl Snapshot {
+    pub fn html(&self) -> Res<String> {
+        let bytes = gunzip(&self.html)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// A stored request/response pair from a capture, with the time of that
+/// capture. Redirects are stored as exchanges of their own.
+#[derive(Deserialize)]
+struct Exchange {
+    link_id: String,
+    position: i64,
+    captured_at: f64,
+    url: String,
+    status: i64,
+    request_headers: String,
+    response_headers: String,
+    /// Gzipped.
+    body: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct LinkId {
+    id: String,
+}
+
+impl Exchange {
+    fn fetched(&self) -> Res<Fetched> {
+        let headers = |json: &str| -> Res<Vec<(String, String)>> {
+            serde_json::from_str(json).map_err(|err| Error::Internal(err.to_string()))
+        };
+        Ok(Fetched {
+            url: self.url.clone(),
+            request_headers: headers(&self.request_headers)?,
+            status: self.status as u16,
+            headers: headers(&self.response_headers)?,
+            body: gunzip(&self.body)?,
+            redirects: vec![],
+        })
+    }
+}
+
+impl Context {
+    /// Captures an offline copy of a link, replacing any earlier one.
+    pub async fn archive_link(&self, link_id: &str, url: &str) -> Res<()> {
+        let capture = capture(self.fetcher.as_ref(), url).await?;
+        let size = capture.html.len() as i64;
+        let html = gzip(capture.html.as_bytes())?;
+        let captured_at = now();
+        let _rows_affected = self
+            .db
+            .execute(
+                "insert into snapshots (link_id, url, html, size, captured_at) values (?, ?, ?, ?, ?)
+                 on conflict (link_id) do update set url = excluded.url, html = excluded.html, size = excluded.size, captured_at = excluded.captured_at",
+                vec![
+                    link_id.into(),
+                    capture.url.into(),
+                    html.into(),
+                    size.into(),
+                    captured_at.into(),
+                ],
+            )
+            .await?;
+        let _rows_affected = self
+            .db
+            .execute(
+                "update links set captured_at = ? where id = ?",
+                vec![captured_at.into(), link_id.into()],
+            )
+            .await?;
+        self.save_exchanges(link_id, &capture.responses).await
+    }
+
+    /// Keeps the raw responses behind a capture for WARC export.
+    async fn save_exchanges(&self, link_id: &str, responses: &[Fetched]) -> Res<()> {
+        let _rows_affected = self
+            .db
+            .execute(
+                "delete from exchanges where link_id = ?",
+                vec![link_id.into()],
+            )
+            .await?;
+        for (position, response) in responses.iter().enumerate() {
+            let json = |headers: &Vec<(String, String)>| -> Res<String> {
+                serde_json::to_string(headers).map_err(|err| Error::Internal(err.to_string()))
+            };
+            let _rows_affected = self
+                .db
+                .execute(
+                    "insert into exchanges (link_id, position, url, status, request_headers, response_headers, body)
+                     values (?, ?, ?, ?, ?, ?, ?)",
+                    vec![
+                        link_id.into(),
+                        (position as i64).into(),
+                        response.url.clone().into(),
+                        (response.status as i64).into(),
+                        json(&response.request_headers)?.into(),
+                        json(&response.headers)?.into(),
+                        gzip(&response.body)?.into(),
+                    ],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Stored exchanges for a link, in the order they happened.
+    async fn exchanges(&self, link_id: &str) -> Res<Vec<Exchange>> {
+        let rows = self
+            .db
+            .query(
+                "select exchanges.*, snapshots.captured_at from exchanges
+                 join links on links.id = exchanges.link_id
+                 join snapshots on snapshots.link_id = exchanges.link_id
+                 where links.user_id = ? and links.id = ?
+                 order by exchanges.position",
+                vec![self.user.id.clone().into(), link_id.into()],
+            )
+            .all()
+            .await?;
+        Ok(rows)
+    }
+
+    /// Links with stored exchanges, oldest first.
+    async fn captured_link_ids(&self) -> Res<Vec<String>> {
+        let rows: Vec<LinkId> = self
+            .db
+            .query(
+                "select links.id from links
+                 where links.user_id = ? and exists (select 1 from exchanges where exchanges.link_id = links.id)
+                 order by links.created_at, links.id",
+                vec![self.user.id.clone().into()],
+            )
+            .all()
+            .await?;
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    async fn snapshot(&self, link_id: &str) -> Res<Option<Snapshot>> {
+        let rows: Vec<Snapshot> = self
+            .db
+            .query(
+                "select html, captured_at from snapshots where link_id = ?",
+                vec![link_id.into()],
+            )
+            .all()
+            .await?;
+        Ok(rows.into_iter().next())
+    }
+}
+
+/// A page with its resources inlined, plus every response it took to build it.
+pub struct Capture {
+    pub url: String,
+    pub html: String,
+    pub responses: Vec<Fetched>,
+}
+
+pub async fn capture(fetcher: &dyn Fetch, url: &str) -> Res<Capture> {
+    let page = fetcher.fetch(url).await?;
+    if !page.is_success() {
+        return Err(Error::Fetch(format!("{} returned {}", url, page.status)));
+    }
+    if let Some(mime) = page.content_type() {
+        if !mime.contains("html") {
+            return Err(Error::Fetch(format!("{} is {}, not a web page", url, mime)));
+        }
+    }
+    let base = Url::parse(&page.url).map_err(|err| Error::Fetch(err.to_string()))?;
+    let html = page.text();
+    let references = references(&html, &base);
+    let mut capturer = Capturer {
+        fetcher,
+        bytes: page.body.len(),
+        responses: page.chain(),
+        seen: HashSet::new(),
+        stylesheets: vec![],
+        resources: HashMap::new(),
+    };
+    for url in references.stylesheets {
+        capturer.stylesheet(url).await;
+    }
+    for css in &references.styles {
+        for reference in css_references(css) {
+            if let Ok(url) = base.join(&reference.url) {
+                capturer.resource(url).await;
+            }
+        }
+    }
+    for url in references.resources {
+        capturer.resource(url).await;
+    }
+    let stylesheets = capturer.inline_stylesheets();
+    let html = inline(&html, &base, &stylesheets, &capturer.resources)?;
+
+    Ok(Capture {
+        url: base.to_string(),
+        html,
+        responses: capturer.responses,
+    })
+}
+
+struct Capturer<'a> {
+    fetcher: &'a dyn Fetch,
+    bytes: usize,
+    responses: Vec<Fetched>,
+    seen: HashSet<String>,
+    /// Fetched stylesheets in the order they were found, importers first.
+    stylesheets: Vec<(Url, String)>,
+    /// Data urls for everything else, by absolute url.
+    resources: HashMap<String, String>,
+}
+
+impl<'a> Capturer<'a> {
+    async fn get(&mut self, url: &Url) -> Option<Fetched> {
+        if !matches!(url.scheme(), "http" | "https")
+            || self.bytes > MAX_CAPTURE_BYTES
+            || !self.seen.insert(url.to_string())
+        {
+            return None;
+        }
+        match self.fetcher.fetch(url.as_str()).await {
+            Ok(response) => {
+                self.bytes += response.body.len();
+                self.responses.extend(response.chain());
+                response.is_success().then_some(response)
+            }
+            Err(err) => {
+                log::warn!("could not fetch {} for archiving: {:?}", url, err);
+                None
+            }
+        }
+    }
+
+    /// Fetches a stylesheet, then whatever it imports or points at.
+    async fn stylesheet(&mut self, url: Url) {
+        let mut queue = vec![(url, 0)];
+        while let Some((url, depth)) = queue.pop() {
+            let Some(response) = self.get(&url).await else {
+                continue;
+            };
+            let css = response.text();
+            for reference in css_references(&css) {
+                let Ok(target) = url.join(&reference.url) else {
+                    continue;
+                };
+                match reference.import {
+                    true if depth < MAX_IMPORT_DEPTH => queue.push((target, depth + 1)),
+                    true => {}
+                    false => self.resource(target).await,
+                }
+            }
+            self.stylesheets.push((url, css));
+        }
+    }
+
+    async fn resource(&mut self, url: Url) {
+        if let Some(response) = self.get(&url).await {
+            let mime = response.content_type().unwrap_or_else(|| {
+                mime_guess::from_path(url.path())
+                    .first_or_octet_stream()
+                    .to_string()
+            });
+            self.resources
+                .insert(url.to_string(), data_url(&mime, &response.body));
+        }
+    }
+
+    /// Rewrites each stylesheet to use data urls, imported ones included.
+    /// Imports are found after their importers, so they're done in reverse.
+    fn inline_stylesheets(&mut self) -> HashMap<String, String> {
+        let mut inlined = HashMap::new();
+        for (url, css) in self.stylesheets.iter().rev() {
+            let css = inline_css(css, url, &self.resources);
+            self.resources
+                .insert(url.to_string(), data_url("text/css", css.as_bytes()));
+            inlined.insert(url.to_string(), css);
+        }
+        inlined
+    }
+}
+
+fn data_url(mime: &str, bytes: &[u8]) -> String {
+    format!("data:{};base64,{}", mime, STANDARD.encode(bytes))
+}
+
+/// What a page points at that's worth keeping.
+struct References {
+    stylesheets: Vec<Url>,
+    /// Contents of `<style>` elements and `style` attributes.
+    styles: Vec<String>,
+    resources: Vec<Url>,
+}
+
+fn references(html: &str, base: &Url) -> References {
+    use scraper::{Html, Selector};
+    let document = Html::parse_document(html);
+    let attrs = |selector: &str, attr: &str| -> Vec<Url> {
+        let Ok(selector) = Selector::parse(selector) else {
+            return vec![];
+        };
+        document
+            .select(&selector)
+            .filter_map(|element| element.value().attr(attr))
+            .filter_map(|value| base.join(value.trim()).ok())
+            .collect()
+    };
+    let stylesheets = attrs("link[rel~=stylesheet][href]", "href");
+    let mut resources = attrs("img[src]", "src");
+    resources.extend(attrs("input[type=image][src]", "src"));
+    resources.extend(attrs("link[rel~=icon][href]", "href"));
+    resources.extend(attrs("video[poster]", "poster"));
+    let mut styles = vec![];
+    if let Ok(selector) = Selector::parse("style") {
+        styles.extend(
+            document
+                .select(&selector)
+                .map(|element| element.text().collect::<String>()),
+        );
+    }
+    if let Ok(selector) = Selector::parse("[style]") {
+        styles.extend(
+            document
+                .select(&selector)
+                .filter_map(|element| element.value().attr("style"))
+                .map(|style| style.to_owned()),
+        );
+    }
+
+    References {
+        stylesheets,
+        styles,
+        resources,
+    }
+}
+
+/// Rewrites the page to use the fetched resources, and strips out anything
+/// that would run code or load something live.
+fn inline(
+    html: &str,
+    base: &Url,
+    stylesheets: &HashMap<String, String>,
+    resources: &HashMap<String, String>,
+) -> Res<String> {
+    let absolute =
+        |value: &str| -> Option<String> { base.join(value.trim()).ok().map(|url| url.to_string()) };
+    let local = |value: &str| -> Option<&String> { resources.get(&absolute(value)?) };
+    let style = RefCell::new(String::new());
+    let handlers = vec![
+        element!(
+            "script, iframe, frame, object, embed, base, meta[http-equiv]",
+            |el| {
+                el.remove();
+                Ok(())
+            }
+        ),
+        element!("link[href]", |el| {
+            let rel = el.get_attribute("rel").unwrap_or_default().to_lowercase();
+            let href = el.get_attribute("href").unwrap_or_default();
+            let rels: Vec<&str> = rel.split_whitespace().collect();
+            if rels.contains(&"stylesheet") {
+                match absolute(&href).and_then(|url| stylesheets.get(&url)) {
+                    Some(css) => el.replace(
+                        &format!("<style>{}</style>", raw_css(css)),
+                        ContentType::Html,
+                    ),
+                    None => el.remove(),
+                }
+            } else if rels.contains(&"icon") {
+                match local(&href) {
+                    Some(data) => el.set_attribute("href", data)?,
+                    None => el.remove(),
+                }
+            } else if !rels.contains(&"canonical") {
+                el.remove();
+            }
+            Ok(())
+        }),
+        element!("img[src], input[type=image][src]", |el| {
+            let src = el.get_attribute("src").unwrap_or_default();
+            match local(&src) {
+                Some(data) => el.set_attribute("src", data)?,
+                None => el.remove_attribute("src"),
+            }
+            el.remove_attribute("srcset");
+            Ok(())
+        }),
+        element!("source", |el| {
+            el.remove_attribute("src");
+            el.remove_attribute("srcset");
+            Ok(())
+        }),
+        element!("img[srcset]", |el| {
+            el.remove_attribute("srcset");
+            Ok(())
+        }),
+        element!("video[poster]", |el| {
+            let poster = el.get_attribute("poster").unwrap_or_default();
+            match local(&poster) {
+                Some(data) => el.set_attribute("poster", data)?,
+                None => el.remove_attribute("poster"),
+            }
+            Ok(())
+        }),
+        element!("a[href], area[href], form[action]", |el| {
+            let attr = match el.has_attribute("href") {
+                true => "href",
+                false => "action",
+            };
+            let value = el.get_attribute(attr).unwrap_or_default();
+            if let Some(url) = absolute(&value) {
+                el.set_attribute(attr, &url)?;
+            }
+            Ok(())
+        }),
+        element!("*", |el| {
+            let handlers: Vec<String> = el
+                .attributes()
+                .iter()
+                .map(|attr| attr.name())
+                .filter(|name| name.starts_with("on"))
+                .collect();
+            for name in handlers {
+                el.remove_attribute(&name);
+            }
+            if let Some(css) = el.get_attribute("style") {
+                el.set_attribute("style", &inline_css(&css, base, resources))?;
+            }
+            Ok(())
+        }),
+        text!("style", |chunk| {
+            style.borrow_mut().push_str(chunk.as_str());
+            match chunk.last_in_text_node() {
+                true => {
+                    let css = std::mem::take(&mut *style.borrow_mut());
+                    chunk.replace(
+                        &raw_css(&inline_css(&css, base, resources)),
+                        ContentType::Html,
+                    );
+                }
+                false => chunk.remove(),
+            }
+            Ok(())
+        }),
+    ];
+
+    rewrite(html, handlers)
+}
+
+/// Adds a bar across the top of a snapshot saying when it was taken.
+fn with_banner(html: &str, url: &str, captured_at: f64) -> Res<String> {
+    let captured = chrono::DateTime::from_timestamp(captured_at as i64, 0)
+        .map(|datetime| datetime.format("%b %-d, %Y at %H:%M UTC").to_string())
+        .unwrap_or_default();
+    let banner = html! {
+        div style="position: sticky; top: 0; z-index: 2147483647; padding: 8px 12px; font: 14px/1.4 system-ui, sans-serif; color: #111827; background: #fed7aa; border-bottom: 1px solid #f97316" {
+            "Archived copy captured " (captured) ". "
+            a style="color: #0369a1; text-decoration: underline" href=(url) { "View the live page" }
+        }
+    }
+    .into_string();
+    let found = Cell::new(false);
+    let html = rewrite(
+        html,
+        vec![element!("body", |el| {
+            if !found.replace(true) {
+                el.prepend(&banner, ContentType::Html);
+            }
+            Ok(())
+        })],
+    )?;
+
+    match found.get() {
+        true => Ok(html),
+        false => Ok(format!("{}{}", banner, html)),
+    }
+}
+
+fn rewrite(
+    html: &str,
+    handlers: Vec<(std::borrow::Cow<Selector>, ElementContentHandlers)>,
+) -> Res<String> {
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: handlers,
+            ..RewriteStrSettings::default()
+        },
+    )
+    .map_err(|err| Error::Internal(err.to_string()))
+}
+
+/// Css can't close the `<style>` element it's placed in.
+fn raw_css(css: &str) -> String {
+    css.replace("</", "<\\/")
+}
+
+/// A `url(...)` or `@import` in a stylesheet.
+struct CssReference {
+    url: String,
+    import: bool,
+}
+
+fn css_references(css: &str) -> Vec<CssReference> {
+    let mut references = vec![];
+    let _css = map_css_references(css, |url, import| {
+        references.push(CssReference {
+            url: url.to_owned(),
+            import,
+        });
+        None
+    });
+    references
+}
+
+/// Points every reference in a stylesheet at the matching data url.
+fn inline_css(css: &str, base: &Url, resources: &HashMap<String, String>) -> String {
+    map_css_references(css, |url, _import| {
+        let url = base.join(url).ok()?;
+        resources.get(url.as_str()).cloned()
+    })
+}
+
+/// Calls `f` with every url a stylesheet refers to, swapping in whatever
+/// it returns.
+fn map_css_references(css: &str, mut f: impl FnMut(&str, bool) -> Option<String>) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    loop {
+        let import = rest.find("@import");
+        let url = rest.find("url(");
+        let (start, is_import) = match (import, url) {
+            (Some(import), Some(url)) if import < url => (import, true),
+            (Some(import), None) => (import, true),
+            (_, Some(url)) => (url, false),
+            (None, None) => break,
+        };
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if is_import {
+            out.push_str("@import");
+            rest = &rest["@import".len()..];
+            let trimmed = rest.trim_start();
+            out.push_str(&rest[..rest.len() - trimmed.len()]);
+            rest = trimmed;
+            if rest.starts_with("url(") {
+                rest = map_url(rest, &mut out, true, &mut f);
+            } else if let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') {
+                let Some(end) = rest[1..].find(quote) else {
+                    break;
+                };
+                let value = &rest[1..1 + end];
+                out.push(quote);
+                match f(value.trim(), true) {
+                    Some(value) => out.push_str(&value),
+                    None => out.push_str(value),
+                }
+                out.push(quote);
+                rest = &rest[end + 2..];
+            }
+        } else {
+            rest = map_url(rest, &mut out, false, &mut f);
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Handles one `url(...)` at the start of `css`, returning what follows it.
+fn map_url<'a>(
+    css: &'a str,
+    out: &mut String,
+    import: bool,
+    f: &mut impl FnMut(&str, bool) -> Option<String>,
+) -> &'a str {
+    let Some(end) = css.find(')') else {
+        out.push_str(css);
+        return "";
+    };
+    let value = css[4..end].trim().trim_matches(|c| c == '"' || c == '\'');
+    match value.starts_with("data:") {
+        true => out.push_str(&css[..=end]),
+        false => match f(value, import) {
+            Some(value) => out.push_str(&format!("url(\"{}\")", value)),
+            None => out.push_str(&css[..=end]),
+        },
+    }
+    &css[end + 1..]
+}
+
+fn gzip(bytes: &[u8]) -> Res<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+fn gunzip(bytes: &[u8]) -> Res<Vec<u8>> {
+    let mut decoder = GzDecoder::new(bytes);
+    let mut out = vec![];
+    let _read = decoder.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Stub;
+
+    fn references_in(css: &str) -> Vec<(String, bool)> {
+        css_references(css)
+            .into_iter()
+            .map(|reference| (reference.url, reference.import))
+            .collect()
+    }
+
+    #[test]
+    fn finds_css_references() {
+        let css = r#"@import "a.css"; @import url('b.css') screen;
+            body { background: url( "c.png" ) } i { background: url(d.png) }
+            b { background: url(data:image/png;base64,AAAA) }"#;
+        assert_eq!(
+            references_in(css),
+            vec![
+                ("a.css".to_owned(), true),
+                ("b.css".to_owned(), true),
+                ("c.png".to_owned(), false),
+                ("d.png".to_owned(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn swaps_css_references() {
+        let css =
+            r#"@import 'a.css'; i { background: url(d.png) } b { background: url(missing.png) }"#;
+        let mapped = map_css_references(css, |url, _import| match url {
+            "a.css" => Some("data:text/css;base64,".to_owned()),
+            "d.png" => Some("data:image/png;base64,".to_owned()),
+            _ => None,
+        });
+        assert_eq!(
+            mapped,
+            r#"@import 'data:text/css;base64,'; i { background: url("data:image/png;base64,") } b { background: url(missing.png) }"#
+        );
+    }
+
+    #[test]
+    fn leaves_broken_css_alone() {
+        for css in [
+            "@import",
+            "@import 'a.css",
+            "i { background: url(a.png }",
+            "url(",
+        ] {
+            assert_eq!(map_css_references(css, |_, _| Some("x".into())), css);
+        }
+    }
+
+    #[tokio::test]
+    async fn captures_a_page_and_its_resources() {
+        let stub = Stub::default()
+            .redirect("https://example.com/", "https://example.com/home")
+            .page(
+                "https://example.com/home",
+                "text/html",
+                br#"<html><head>
+                <link rel="stylesheet" href="/style.css">
+                <script src="/app.js"></script>
+                </head><body onload="track()">
+                <img src="logo.png" srcset="logo@2x.png 2x">
+                <a href="/about">About</a>
+                </body></html>"#,
+            )
+            .page(
+                "https://example.com/style.css",
+                "text/css",
+                b"@import 'fonts.css'; body { background: url(bg.png) }",
+            )
+            .page(
+                "https://example.com/fonts.css",
+                "text/css",
+                b"b { font-weight: bold }",
+            )
+            .page("https://example.com/bg.png", "image/png", b"bg")
+            .page("https://example.com/logo.png", "image/png", b"logo");
+
+        let capture = capture(&stub, "https://example.com/").await.unwrap();
+        assert_eq!(capture.url, "https://example.com/home");
+        let html = &capture.html;
+        assert!(!html.contains("script"));
+        assert!(!html.contains("onload"));
+        assert!(!html.contains("srcset"));
+        assert!(html.contains(&format!(r#"src="{}""#, data_url("image/png", b"logo"))));
+        assert!(html.contains(r#"href="https://example.com/about""#));
+        assert!(html.contains(&data_url("image/png", b"bg")));
+        assert!(html.contains(&data_url("text/css", b"b { font-weight: bold }")));
+
+        let urls: Vec<&str> = capture
+            .responses
+            .iter()
+            .map(|response| response.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/",
+                "https://example.com/home",
+                "https://example.com/style.css",
+                "https://example.com/bg.png",
+                "https://example.com/fonts.css",
+                "https://example.com/logo.png",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_captures_web_pages() {
+        let stub = Stub::default().page("https://example.com/a.pdf", "application/pdf", b"%PDF");
+        assert!(capture(&stub, "https://example.com/a.pdf").await.is_err());
+        assert!(capture(&stub, "https://example.com/missing").await.is_err());
+    }
+}
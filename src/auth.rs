@@ -0,0 +1,530 @@
+//! Password accounts and cookie sessions.
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header, request::Parts, HeaderMap, Method},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use maud::{html, Markup};
+use rizz::Database;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{button, config, database, layout, now, Component, Error, Res, Route};
+
+const SESSION_COOKIE: &str = "links_session";
+const SESSION_SECONDS: f64 = 60.0 * 60.0 * 24.0 * 30.0;
+
+#[derive(Clone)]
+pub enum AuthRoute {
+    Login,
+    Register,
+    Logout,
+}
+
+impl std::fmt::Display for AuthRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let x: &str = self.to_owned().into();
+        f.write_str(x)
+    }
+}
+
+impl From<AuthRoute> for &'static str {
+    fn from(value: AuthRoute) -> Self {
+        match value {
+            AuthRoute::Login => "/login",
+            AuthRoute::Register => "/register",
+            AuthRoute::Logout => "/logout",
+        }
+    }
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route(AuthRoute::Login.into(), get(login).post(create_session))
+        .route(AuthRoute::Register.into(), get(register).post(create_user))
+        .route(AuthRoute::Logout.into(), post(logout))
+}
+
+#[allow(unused)]
+#[derive(Deserialize, Clone)]
+pub struct User {
+    pub id: String,
+    pub email: String,
+    password_hash: String,
+    pub created_at: f64,
+}
+
+/// Extractor for the pages you can see while signed out.
+struct Guest {
+    db: Database,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Guest {
+    type Rejection = Error;
+
+    async fn from_request_parts(_parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Guest {
+            db: database().await?,
+        })
+    }
+}
+
+struct AuthComponent {
+    route: AuthRoute,
+    email: String,
+    next: String,
+    error: Option<String>,
+}
+
+impl Component for AuthComponent {
+    fn html(&self) -> Markup {
+        let (heading, action, other, other_label) = match self.route {
+            AuthRoute::Register => (
+                "Create an account",
+                "Sign up",
+                AuthRoute::Login,
+                "Already have an account? Log in",
+            ),
+            _ => (
+                "Log in",
+                "Log in",
+                AuthRoute::Register,
+                "Need an account? Sign up",
+            ),
+        };
+        html! {
+            h2 class="text-xl" { (heading) }
+            form class="flex flex-col w-full gap-3" action=(self.route) method="post" hx-ext="ignore:json-enc" {
+                input type="email" class="p-2 py-3 text-xl bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="email" value=(self.email) placeholder="email" autofocus required;
+                input type="password" class="p-2 py-3 text-xl bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="password" placeholder="password" required;
+                input type="hidden" name="next" value=(self.next);
+                @if let Some(err) = &self.error {
+                    p class="text-red-500" { (err) }
+                }
+                (button(action))
+            }
+            a class="text-sky-500 underline hover:text-sky-300" href=(format!("{}?next={}", other, self.next)) { (other_label) }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NextParams {
+    #[serde(default)]
+    next: String,
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    email: String,
+    password: String,
+    #[serde(default)]
+    next: String,
+}
+
+async fn login(Query(params): Query<NextParams>) -> Markup {
+    let component = AuthComponent {
+        route: AuthRoute::Login,
+        email: String::new(),
+        next: params.next,
+        error: None,
+    };
+
+    layout(component, None)
+}
+
+async fn create_session(guest: Guest, Form(params): Form<Credentials>) -> Res<Response> {
+    let email = params.email.trim().to_lowercase();
+    let user = user_by_email(&guest.db, &email).await?;
+    let verified = match &user {
+        Some(user) if !user.password_hash.is_empty() => {
+            verify_password(&params.password, &user.password_hash).await?
+        }
+        _ => false,
+    };
+    let Some(user) = user.filter(|_| verified) else {
+        let component = AuthComponent {
+            route: AuthRoute::Login,
+            email,
+            next: params.next,
+            error: Some("Wrong email or password".into()),
+        };
+        return Ok(layout(component, None).into_response());
+    };
+    start_session(&guest.db, &user.id, &params.next).await
+}
+
+async fn register(Query(params): Query<NextParams>) -> Markup {
+    let component = AuthComponent {
+        route: AuthRoute::Register,
+        email: String::new(),
+        next: params.next,
+        error: None,
+    };
+
+    layout(component, None)
+}
+
+async fn create_user(guest: Guest, Form(params): Form<Credentials>) -> Res<Response> {
+    let email = params.email.trim().to_lowercase();
+    if let Some(error) = credentials_error(&guest.db, &email, &params.password).await? {
+        let component = AuthComponent {
+            route: AuthRoute::Register,
+            email,
+            next: params.next,
+            error: Some(error.into()),
+        };
+        return Ok(layout(component, None).into_response());
+    }
+    let password_hash = hash_password(params.password).await?;
+    let user_id = nanoid::nanoid!();
+    let _rows_affected = guest
+        .db
+        .execute(
+            "insert into users (id, email, password_hash, created_at) values (?, ?, ?, ?)",
+            vec![
+                user_id.clone().into(),
+                email.into(),
+                password_hash.into(),
+                now().into(),
+            ],
+        )
+        .await?;
+    start_session(&guest.db, &user_id, &params.next).await
+}
+
+async fn credentials_error(
+    db: &Database,
+    email: &str,
+    password: &str,
+) -> Res<Option<&'static str>> {
+    let error = if !email.contains('@') {
+        Some("That doesn't look like an email address")
+    } else if password.chars().count() < 8 {
+        Some("Passwords need at least 8 characters")
+    } else if user_by_email(db, email).await?.is_some() {
+        Some("There is already an account with that email")
+    } else {
+        None
+    };
+    Ok(error)
+}
+
+/// Sets up the admin account from the command line. If the ownership
+/// migration handed pre-existing links to a placeholder account, that
+/// account gets the email and password; otherwise a new one is made.
+/// Sign up never touches the placeholder, so nobody can take its links
+/// over from the web.
+pub async fn create_admin(db: &Database, email: &str, password: String) -> Res<()> {
+    let email = email.trim().to_lowercase();
+    if let Some(error) = credentials_error(db, &email, &password).await? {
+        return Err(Error::BadRequest(error.into()));
+    }
+    let password_hash = hash_password(password).await?;
+    let _rows_affected = match unclaimed_admin(db).await? {
+        Some(admin) => {
+            db.execute(
+                "update users set email = ?, password_hash = ? where id = ?",
+                vec![email.into(), password_hash.into(), admin.id.into()],
+            )
+            .await?
+        }
+        None => {
+            db.execute(
+                "insert into users (id, email, password_hash, created_at) values (?, ?, ?, ?)",
+                vec![
+                    nanoid::nanoid!().into(),
+                    email.into(),
+                    password_hash.into(),
+                    now().into(),
+                ],
+            )
+            .await?
+        }
+    };
+    Ok(())
+}
+
+async fn logout(guest: Guest, headers: HeaderMap) -> Res<Response> {
+    if let Some(token) = cookie(&headers, SESSION_COOKIE) {
+        let _rows_affected = guest
+            .db
+            .execute(
+                "delete from sessions where id = ?",
+                vec![sha256(token).into()],
+            )
+            .await?;
+    }
+    Ok((
+        [(header::SET_COOKIE, session_cookie("", 0))],
+        Redirect::to(AuthRoute::Login.into()),
+    )
+        .into_response())
+}
+
+/// Looks up the user behind the session cookie, if it is still valid.
+pub async fn session_user(db: &Database, headers: &HeaderMap) -> Res<Option<User>> {
+    let Some(token) = cookie(headers, SESSION_COOKIE) else {
+        return Ok(None);
+    };
+    let rows: Vec<User> = db
+        .query(
+            "select users.* from users
+             join sessions on sessions.user_id = users.id
+             where sessions.id = ? and sessions.expires_at > ?",
+            vec![sha256(token).into(), now().into()],
+        )
+        .all()
+        .await?;
+    Ok(rows.into_iter().next())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scope {
+    LinksRead,
+    LinksWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 2] = [Scope::LinksRead, Scope::LinksWrite];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::LinksRead => "links:read",
+            Scope::LinksWrite => "links:write",
+        }
+    }
+
+    /// Reading is enough for safe methods, everything else changes links.
+    pub fn for_method(method: &Method) -> Self {
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => Scope::LinksRead,
+            _ => Scope::LinksWrite,
+        }
+    }
+}
+
+#[allow(unused)]
+#[derive(Deserialize, Clone)]
+pub struct ApiToken {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    token_hash: String,
+    /// Space separated, e.g. `links:read links:write`.
+    pub scopes: String,
+    pub created_at: f64,
+    pub last_used_at: Option<f64>,
+    pub revoked_at: Option<f64>,
+}
+
+impl ApiToken {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.split_whitespace().any(|s| s == scope.as_str())
+    }
+}
+
+/// The api token from an `Authorization: Bearer` header.
+pub fn api_token(parts: &Parts) -> Option<String> {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+}
+
+/// The api token from a pinboard style `auth_token=user:token` query
+/// parameter. Only the pinboard routes take it, since tokens in urls end up
+/// in access logs and `Referer` headers.
+pub fn query_token(parts: &Parts) -> Option<String> {
+    url::form_urlencoded::parse(parts.uri.query()?.as_bytes())
+        .find(|(key, _)| key == "auth_token")
+        .map(|(_, value)| match value.rsplit_once(':') {
+            Some((_, token)) => token.to_owned(),
+            None => value.into_owned(),
+        })
+}
+
+/// Looks up the user behind an unrevoked api token and records the use.
+pub async fn token_user(db: &Database, token: &str) -> Res<Option<(User, ApiToken)>> {
+    let hash = sha256(token);
+    let tokens: Vec<ApiToken> = db
+        .query(
+            "select * from api_tokens where token_hash = ? and revoked_at is null",
+            vec![hash.into()],
+        )
+        .all()
+        .await?;
+    let Some(token) = tokens.into_iter().next() else {
+        return Ok(None);
+    };
+    let users: Vec<User> = db
+        .query(
+            "select * from users where id = ?",
+            vec![token.user_id.clone().into()],
+        )
+        .all()
+        .await?;
+    let Some(user) = users.into_iter().next() else {
+        return Ok(None);
+    };
+    let _rows_affected = db
+        .execute(
+            "update api_tokens set last_used_at = ? where id = ?",
+            vec![now().into(), token.id.clone().into()],
+        )
+        .await?;
+    Ok(Some((user, token)))
+}
+
+/// Creates a token and returns it with its plaintext, which is only ever
+/// shown this once.
+pub async fn create_token(
+    db: &Database,
+    user_id: &str,
+    name: &str,
+    scopes: &[Scope],
+) -> Res<String> {
+    let token = format!("lnk_{}", nanoid::nanoid!(40));
+    let scopes: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+    let _rows_affected = db
+        .execute(
+            "insert into api_tokens (id, user_id, name, token_hash, scopes, created_at) values (?, ?, ?, ?, ?, ?)",
+            vec![
+                nanoid::nanoid!().into(),
+                user_id.into(),
+                name.into(),
+                sha256(&token).into(),
+                scopes.join(" ").into(),
+                now().into(),
+            ],
+        )
+        .await?;
+    Ok(token)
+}
+
+pub fn login_url(next: &str) -> String {
+    let next: String = url::form_urlencoded::byte_serialize(next.as_bytes()).collect();
+    format!("{}?next={}", AuthRoute::Login, next)
+}
+
+async fn user_by_email(db: &Database, email: &str) -> Res<Option<User>> {
+    let rows: Vec<User> = db
+        .query("select * from users where email = ?", vec![email.into()])
+        .all()
+        .await?;
+    Ok(rows.into_iter().next())
+}
+
+/// The placeholder account the ownership migration hands pre-existing
+/// links to, until `links --create-admin` claims it.
+pub async fn unclaimed_admin(db: &Database) -> Res<Option<User>> {
+    let rows: Vec<User> = db
+        .query(
+            "select * from users where password_hash = '' limit 1",
+            vec![],
+        )
+        .all()
+        .await?;
+    Ok(rows.into_iter().next())
+}
+
+/// Stores a new session and redirects to `next` with its cookie set. Only
+/// the hash of the token is kept, so a leaked database can't sign anyone in.
+async fn start_session(db: &Database, user_id: &str, next: &str) -> Res<Response> {
+    let token = nanoid::nanoid!(32);
+    let _rows_affected = db
+        .execute(
+            "delete from sessions where expires_at <= ?",
+            vec![now().into()],
+        )
+        .await?;
+    let _rows_affected = db
+        .execute(
+            "insert into sessions (id, user_id, created_at, expires_at) values (?, ?, ?, ?)",
+            vec![
+                sha256(&token).into(),
+                user_id.into(),
+                now().into(),
+                (now() + SESSION_SECONDS).into(),
+            ],
+        )
+        .await?;
+    let next = match next.starts_with('/') && !next.starts_with("//") {
+        true => next,
+        false => Route::Home.into(),
+    };
+    Ok((
+        [(
+            header::SET_COOKIE,
+            session_cookie(&token, SESSION_SECONDS as i64),
+        )],
+        Redirect::to(next),
+    )
+        .into_response())
+}
+
+fn session_cookie(token: &str, max_age: i64) -> String {
+    let secure = match config().base_url.starts_with("https://") {
+        true => "; Secure",
+        false => "",
+    };
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        SESSION_COOKIE, token, max_age, secure
+    )
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+pub fn sha256(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+async fn hash_password(password: String) -> Res<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| Error::Internal(format!("hashing password: {}", err)))
+    })
+    .await
+    .map_err(|err| Error::Internal(err.to_string()))?
+}
+
+async fn verify_password(password: &str, hash: &str) -> Res<bool> {
+    let password = password.to_owned();
+    let hash = hash.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash)
+            .map_err(|err| Error::Internal(format!("parsing password hash: {}", err)))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await
+    .map_err(|err| Error::Internal(err.to_string()))?
+}
@@ -0,0 +1,230 @@
+//! Reading and writing the Netscape bookmark file format every browser
+//! imports and exports.
+
+pub struct Bookmark {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub add_date: Option<f64>,
+    pub tags: Vec<String>,
+}
+
+/// Parses a bookmarks.html file. Folders the bookmark sits in become tags,
+/// alongside anything in its TAGS attribute.
+pub fn parse(html: &str) -> Vec<Bookmark> {
+    let mut bookmarks: Vec<Bookmark> = vec![];
+    let mut folders: Vec<Option<String>> = vec![];
+    let mut folder: Option<String> = None;
+    let mut last_was_link = false;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        let name = tag
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match name.as_str() {
+            "h3" => {
+                let (text, after) = until_close(rest, "</h3");
+                rest = after;
+                let toolbar = attribute(tag, "personal_toolbar_folder").is_some();
+                folder = match toolbar {
+                    true => None,
+                    false => Some(unescape(text.trim())),
+                };
+                last_was_link = false;
+            }
+            "dl" => folders.push(folder.take()),
+            "/dl" => {
+                let _folder = folders.pop();
+            }
+            "a" => {
+                let (text, after) = until_close(rest, "</a");
+                rest = after;
+                let Some(url) = attribute(tag, "href") else {
+                    continue;
+                };
+                let mut tags: Vec<String> = folders.iter().flatten().cloned().collect();
+                if let Some(names) = attribute(tag, "tags") {
+                    tags.extend(names.split(',').map(|name| name.trim().to_owned()));
+                }
+                let title = unescape(text.trim());
+                bookmarks.push(Bookmark {
+                    url,
+                    title: Some(title).filter(|title| !title.is_empty()),
+                    description: None,
+                    add_date: attribute(tag, "add_date").and_then(|date| date.parse().ok()),
+                    tags,
+                });
+                last_was_link = true;
+            }
+            "dd" if last_was_link => {
+                let end = rest.find('<').unwrap_or(rest.len());
+                let description = unescape(rest[..end].trim());
+                if let Some(bookmark) = bookmarks.last_mut() {
+                    bookmark.description = Some(description).filter(|text| !text.is_empty());
+                }
+                last_was_link = false;
+            }
+            "dt" | "p" | "/dt" | "/p" => {}
+            _ => last_was_link = false,
+        }
+    }
+    bookmarks
+}
+
+/// Writes bookmarks in the same format `parse` reads.
+pub fn export(bookmarks: &[Bookmark]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+         <META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n\
+         <TITLE>Bookmarks</TITLE>\n\
+         <H1>Bookmarks</H1>\n\
+         <DL><p>\n",
+    );
+    for bookmark in bookmarks {
+        html.push_str(&format!("    <DT><A HREF=\"{}\"", escape(&bookmark.url)));
+        if let Some(add_date) = bookmark.add_date {
+            html.push_str(&format!(" ADD_DATE=\"{}\"", add_date as i64));
+        }
+        if !bookmark.tags.is_empty() {
+            html.push_str(&format!(" TAGS=\"{}\"", escape(&bookmark.tags.join(","))));
+        }
+        let title = bookmark.title.as_deref().unwrap_or(&bookmark.url);
+        html.push_str(&format!(">{}</A>\n", escape(title)));
+        if let Some(description) = &bookmark.description {
+            html.push_str(&format!("    <DD>{}\n", escape(description)));
+        }
+    }
+    html.push_str("</DL><p>\n");
+    html
+}
+
+/// Splits `html` at the closing tag `close`, given in lowercase.
+fn until_close<'a>(html: &'a str, close: &str) -> (&'a str, &'a str) {
+    // ascii lowercasing keeps byte offsets lined up with the original
+    let end = html.to_ascii_lowercase().find(close).unwrap_or(html.len());
+    let after = html[end..]
+        .find('>')
+        .map(|i| &html[end + i + 1..])
+        .unwrap_or("");
+    (&html[..end], after)
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find(name) {
+        let start = from + i;
+        from = start + name.len();
+        let boundary = lower[..start].ends_with(char::is_whitespace);
+        if !boundary {
+            continue;
+        }
+        let rest = tag[from..].trim_start();
+        let Some(rest) = rest.strip_prefix('=') else {
+            return Some(String::new());
+        };
+        let rest = rest.trim_start();
+        let value = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => rest[1..].split(quote).next().unwrap_or_default(),
+            _ => rest.split_whitespace().next().unwrap_or_default(),
+        };
+        return Some(unescape(value));
+    }
+    None
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIREFOX: &str = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks Menu</H1>
+<DL><p>
+    <DT><H3 ADD_DATE="1600000000" PERSONAL_TOOLBAR_FOLDER="true">Bookmarks Toolbar</H3>
+    <DL><p>
+        <DT><A HREF="https://example.com/a" ADD_DATE="1600000001" TAGS="rust,web">Example &amp; co</A>
+        <DD>A description
+        <DT><H3>Read/Watch</H3>
+        <DL><p>
+            <DT><A HREF='https://example.com/b'>B</A>
+        </DL><p>
+    </DL><p>
+    <DT><A HREF="https://example.com/c"></A>
+</DL><p>
+"#;
+
+    #[test]
+    fn parses_folders_tags_and_descriptions() {
+        let bookmarks = parse(FIREFOX);
+        assert_eq!(bookmarks.len(), 3);
+
+        assert_eq!(bookmarks[0].url, "https://example.com/a");
+        assert_eq!(bookmarks[0].title.as_deref(), Some("Example & co"));
+        assert_eq!(bookmarks[0].description.as_deref(), Some("A description"));
+        assert_eq!(bookmarks[0].add_date, Some(1600000001.0));
+        assert_eq!(bookmarks[0].tags, vec!["rust", "web"]);
+
+        assert_eq!(bookmarks[1].url, "https://example.com/b");
+        assert_eq!(bookmarks[1].description, None);
+        assert_eq!(bookmarks[1].tags, vec!["Read/Watch"]);
+
+        assert_eq!(bookmarks[2].title, None);
+        assert!(bookmarks[2].tags.is_empty());
+    }
+
+    #[test]
+    fn attributes_after_non_ascii_text() {
+        // the kelvin sign lowercases to a shorter 'k'
+        let bookmarks = parse(r#"<DL><DT><A TITLE="KKKKK" HREF="https://example.com">K</A></DL>"#);
+        assert_eq!(bookmarks.len(), 1);
+        assert_eq!(bookmarks[0].url, "https://example.com");
+    }
+
+    #[test]
+    fn titles_with_non_ascii_text() {
+        // and the dotted capital i lowercases to something longer
+        let bookmarks = parse(r#"<DL><DT><A HREF="https://example.com">İİİİ title</A></DL>"#);
+        assert_eq!(bookmarks[0].title.as_deref(), Some("İİİİ title"));
+    }
+
+    #[test]
+    fn export_round_trips() {
+        let bookmarks = parse(FIREFOX);
+        let again = parse(&export(&bookmarks));
+        assert_eq!(again.len(), bookmarks.len());
+        for (a, b) in bookmarks.iter().zip(&again) {
+            assert_eq!(a.url, b.url);
+            assert_eq!(a.description, b.description);
+            assert_eq!(a.add_date, b.add_date);
+            assert_eq!(a.tags, b.tags);
+        }
+        assert_eq!(again[0].title.as_deref(), Some("Example & co"));
+        // untitled bookmarks are exported with their url as the title
+        assert_eq!(again[2].title.as_deref(), Some("https://example.com/c"));
+    }
+}
@@ -0,0 +1,377 @@
+//! Dead link checking. Every http(s) link is re-checked now and then
+//! through the job queue, and ones that keep failing show up at `/broken`,
+//! where they can be swapped for their archived copy or deleted.
+
+use std::time::Duration;
+
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Router,
+};
+use maud::{html, Markup};
+use rizz::Database;
+use serde::Deserialize;
+
+use crate::{date, now, Component, Context, Error, Html, Link, Res, Route};
+
+/// Links are checked again once their last check is this old.
+const RECHECK_AFTER: f64 = 7.0 * 24.0 * 60.0 * 60.0;
+
+/// How often to look for links that are due a check.
+const SCHEDULE_EVERY: Duration = Duration::from_secs(60 * 60);
+
+/// Checks queued per round, so a big import doesn't flood the queue.
+const BATCH: i64 = 500;
+
+/// Consecutive failed checks before a link counts as broken, so a site
+/// being down for an afternoon doesn't flag it.
+pub const BROKEN_AFTER: i64 = 2;
+
+/// Matches the links that can be checked at all; `mailto:` and friends
+/// can't be fetched.
+const CHECKABLE: &str = "(links.url like 'http://%' or links.url like 'https://%')";
+
+#[derive(Clone)]
+pub enum BrokenRoute {
+    Broken,
+    Replace,
+}
+
+impl std::fmt::Display for BrokenRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let x: &str = self.to_owned().into();
+        f.write_str(x)
+    }
+}
+
+impl From<BrokenRoute> for &'static str {
+    fn from(value: BrokenRoute) -> Self {
+        match value {
+            BrokenRoute::Broken => "/broken",
+            BrokenRoute::Replace => "/links/:id/replace",
+        }
+    }
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route(BrokenRoute::Broken.into(), get(broken))
+        .route(BrokenRoute::Replace.into(), post(replace))
+}
+
+fn replace_url(id: &str) -> String {
+    let route: &str = BrokenRoute::Replace.into();
+    route.replace(":id", id)
+}
+
+/// Queues checks for links that are due one, every so often.
+pub fn start(db: Database) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = schedule(&db).await {
+                log::error!("could not schedule link checks: {:?}", err);
+            }
+            tokio::time::sleep(SCHEDULE_EVERY).await;
+        }
+    });
+}
+
+async fn schedule(db: &Database) -> Res<()> {
+    let now = now();
+    let sql = format!(
+        "insert into jobs (id, user_id, kind, link_id, status, attempts, run_at, created_at, updated_at)
+         select lower(hex(randomblob(16))), links.user_id, 'check', links.id, 'queued', 0, ?, ?, ?
+         from links
+         where links.replaced_at is null
+           and {}
+           and (links.checked_at is null or links.checked_at < ?)
+           and not exists (
+               select 1 from jobs
+               where jobs.kind = 'check' and jobs.link_id = links.id and jobs.status in ('queued', 'running')
+           )
+         order by links.checked_at is not null, links.checked_at
+         limit ?",
+        CHECKABLE
+    );
+    let rows_affected = db
+        .execute(
+            &sql,
+            vec![
+                now.into(),
+                now.into(),
+                now.into(),
+                (now - RECHECK_AFTER).into(),
+                BATCH.into(),
+            ],
+        )
+        .await?;
+    if rows_affected > 0 {
+        log::info!("queued {} link checks", rows_affected);
+    }
+    Ok(())
+}
+
+impl Context {
+    /// Checks a link with a HEAD request, falling back to GET since plenty
+    /// of servers refuse or mishandle HEAD. A failed check is recorded, not
+    /// returned as an error; only database trouble is.
+    pub async fn check_link(&self, link: &Link) -> Res<()> {
+        let response = match self.fetcher.head(&link.url).await {
+            Ok(response) if response.is_success() => Ok(response),
+            _ => self.fetcher.fetch(&link.url).await,
+        };
+        let (status, final_url, ok) = match &response {
+            Ok(response) => (
+                Some(response.status as i64),
+                Some(response.url.clone()),
+                response.is_success(),
+            ),
+            Err(err) => {
+                log::info!("check failed for {}: {:?}", link.url, err);
+                (None, None, false)
+            }
+        };
+        let failures = match ok {
+            true => 0,
+            false => link.check_failures + 1,
+        };
+        let _rows_affected = self
+            .db
+            .execute(
+                "update links set check_status = ?, check_url = ?, checked_at = ?, check_failures = ? where id = ?",
+                vec![
+                    status.into(),
+                    final_url.into(),
+                    now().into(),
+                    failures.into(),
+                    link.id.clone().into(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn broken_count(&self) -> Res<i64> {
+        let sql = format!(
+            "select count(*) as count from links
+             where user_id = ? and replaced_at is null and check_failures >= ? and {}",
+            CHECKABLE
+        );
+        let rows: Vec<Count> = self
+            .db
+            .query(&sql, vec![self.user.id.clone().into(), BROKEN_AFTER.into()])
+            .all()
+            .await?;
+        Ok(rows.first().map(|row| row.count).unwrap_or_default())
+    }
+
+    async fn broken_links(&self) -> Res<Vec<Link>> {
+        let sql = format!(
+            "select * from links
+             where user_id = ? and replaced_at is null and check_failures >= ? and {}
+             order by checked_at desc",
+            CHECKABLE
+        );
+        let rows = self
+            .db
+            .query(&sql, vec![self.user.id.clone().into(), BROKEN_AFTER.into()])
+            .all()
+            .await?;
+        Ok(rows)
+    }
+}
+
+#[derive(Deserialize)]
+struct Count {
+    count: i64,
+}
+
+struct BrokenComponent {
+    links: Vec<Link>,
+}
+
+impl Component for BrokenComponent {
+    fn html(&self) -> Markup {
+        html! {
+            h2 class="text-xl" { "Broken links" }
+            @if self.links.is_empty() {
+                p class="text-gray-500" { "Every link checked out fine." }
+            }
+            div class="w-full flex flex-col gap-4 divide-y dark:divide-gray-700 divide-gray-200" {
+                @for link in &self.links {
+                    (broken_row(link))
+                }
+            }
+        }
+    }
+}
+
+fn broken_row(link: &Link) -> Markup {
+    html! {
+        div id=(link.dom_id()) class="flex flex-col gap-1 pt-4" {
+            a class="text-xl text-sky-500 underline hover:text-sky-300 break-words" href=(link.url) {
+                (link.display_title())
+            }
+            span class="text-sm text-gray-500 truncate" { (link.url) }
+            span class="text-sm text-red-500" {
+                @match link.check_status {
+                    Some(status) => { "returned " (status) }
+                    None => { "couldn't connect" }
+                }
+                " " (link.check_failures) " checks in a row"
+                @if let Some(checked_at) = link.checked_at {
+                    ", last on " (date(checked_at))
+                }
+            }
+            @if let Some(check_url) = link.check_url.as_ref().filter(|url| **url != link.url) {
+                span class="text-sm text-gray-500 truncate" { "ends up at " (check_url) }
+            }
+            div class="flex gap-3 text-sm text-gray-500" {
+                @if link.captured_at.is_some() {
+                    button type="button" class="hover:text-sky-500" hx-post=(replace_url(&link.id)) hx-target=(format!("#{}", link.dom_id())) hx-swap="outerHTML" {
+                        "replace with archived copy"
+                    }
+                }
+                button type="button" class="hover:text-red-500" hx-delete=(Route::Link.path(&link.id)) hx-target=(format!("#{}", link.dom_id())) hx-swap="outerHTML" hx-confirm="Delete this link?" {
+                    "delete"
+                }
+            }
+        }
+    }
+}
+
+async fn broken(cx: Context) -> Html {
+    let links = cx.broken_links().await?;
+    cx.render(BrokenComponent { links })
+}
+
+/// Points the link at its archived copy from now on, and stops checking it.
+async fn replace(cx: Context, Path(id): Path<String>) -> Res<Response> {
+    let link = cx.link(&id).await?;
+    if link.captured_at.is_none() {
+        return Err(Error::Validation(
+            "there's no archived copy of this link yet".into(),
+        ));
+    }
+    let _rows_affected = cx
+        .db
+        .execute(
+            "update links set replaced_at = ?, check_failures = 0 where id = ? and user_id = ?",
+            vec![now().into(), link.id.into(), cx.user.id.clone().into()],
+        )
+        .await?;
+    match cx.htmx {
+        true => Ok(html! {}.into_response()),
+        false => Ok(Redirect::to(BrokenRoute::Broken.into()).into_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::{
+        jobs::Job,
+        tests::{memory_db, signed_in, Stub},
+    };
+
+    const URL: &str = "https://example.com/a";
+
+    /// Checks a link through `stub` and returns it as stored afterwards.
+    async fn checked(cx: &Context, stub: Stub, id: &str) -> Link {
+        let cx = Context {
+            fetcher: Arc::new(stub),
+            ..cx.clone()
+        };
+        let link = cx.link(id).await.unwrap();
+        cx.check_link(&link).await.unwrap();
+        cx.link(id).await.unwrap()
+    }
+
+    async fn saved(cx: &Context, url: &str) -> Link {
+        cx.create_link(Link::new(&cx.user.id, url.into()), &[])
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_get_when_head_is_refused() {
+        let db = memory_db().await;
+        let cx = signed_in(&db, Stub::default()).await;
+        let link = saved(&cx, URL).await;
+
+        let stub = Stub::default().page(URL, "text/html", b"a").get_only(URL);
+        let link = checked(&cx, stub, &link.id).await;
+        assert_eq!(link.check_status, Some(200));
+        assert_eq!(link.check_url.as_deref(), Some(URL));
+        assert_eq!(link.check_failures, 0);
+        assert!(link.checked_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn counts_failed_checks_in_a_row() {
+        let db = memory_db().await;
+        let cx = signed_in(&db, Stub::default()).await;
+        let link = saved(&cx, URL).await;
+
+        for failures in 1..=BROKEN_AFTER {
+            let link = checked(&cx, Stub::default(), &link.id).await;
+            assert_eq!(link.check_status, Some(404));
+            assert_eq!(link.check_failures, failures);
+            let broken = cx.broken_count().await.unwrap();
+            assert_eq!(broken, (failures >= BROKEN_AFTER) as i64);
+        }
+        assert_eq!(cx.broken_links().await.unwrap().len(), 1);
+
+        let stub = Stub::default().page(URL, "text/html", b"a");
+        let link = checked(&cx, stub, &link.id).await;
+        assert_eq!(link.check_status, Some(200));
+        assert_eq!(link.check_failures, 0);
+        assert_eq!(cx.broken_count().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn replaces_broken_links_with_their_archived_copy() {
+        let db = memory_db().await;
+        let stub = Stub::default().page(URL, "text/html", b"<title>A</title>");
+        let cx = signed_in(&db, stub).await;
+        let link = saved(&cx, URL).await;
+        let other = saved(&cx, "https://example.com/b").await;
+
+        // there's nothing to swap in until the page has been archived
+        let result = replace(cx.clone(), Path(link.id.clone())).await;
+        assert!(matches!(result, Err(Error::Validation(_))));
+
+        cx.archive_link(&link.id, URL).await.unwrap();
+        for _ in 0..BROKEN_AFTER {
+            let _link = checked(&cx, Stub::default(), &link.id).await;
+        }
+        assert_eq!(cx.broken_count().await.unwrap(), 1);
+
+        let response = replace(cx.clone(), Path(link.id.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let link = cx.link(&link.id).await.unwrap();
+        assert!(link.replaced_at.is_some());
+        assert_eq!(link.check_failures, 0);
+        assert!(cx.broken_links().await.unwrap().is_empty());
+
+        // replaced links aren't checked again, everything else is
+        let _rows_affected = db
+            .execute("update links set checked_at = 0", vec![])
+            .await
+            .unwrap();
+        schedule(&db).await.unwrap();
+        let rows: Vec<Job> = db
+            .query("select * from jobs where kind = 'check'", vec![])
+            .all()
+            .await
+            .unwrap();
+        let checked: Vec<&str> = rows.iter().map(|job| job.link_id.as_str()).collect();
+        assert_eq!(checked, vec![other.id.as_str()]);
+    }
+}
@@ -0,0 +1,418 @@
+//! Runtime configuration.
+//!
+//! Settings are layered, each layer overriding the one before it:
+//!
+//! 1. built in defaults
+//! 2. a toml file, `links.toml` in the working directory or whatever
+//!    `--config` / `LINKS_CONFIG` points at
+//! 3. `LINKS_*` environment variables
+//! 4. command line flags
+//!
+//! ```toml
+//! listen = "127.0.0.1:9007"
+//! database = "db.sqlite3"
+//! base_url = "http://localhost:9007"
+//! log_level = "info"
+//! trackers = ["ref", "si"]
+//! allowed_schemes = ["http", "https", "mailto"]
+//! max_url_length = 2048
+//! workers = 2
+//! block_private_addresses = true
+//!
+//! [sqlite]
+//! journal_mode = "wal"
+//! synchronous = "normal"
+//! foreign_keys = true
+//! ```
+
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::{Error, Res};
+
+pub const USAGE: &str =
+    "usage: links [options] [--pending | --rollback <version> | --create-admin <email>]
+
+options:
+  --config <path>        toml config file (LINKS_CONFIG, default links.toml)
+  --listen <addr>        address to bind (LINKS_LISTEN, default 127.0.0.1:9007)
+  --database <path>      sqlite database file (LINKS_DATABASE, default db.sqlite3)
+  --base-url <url>       public url of this instance (LINKS_BASE_URL)
+  --log-level <level>    error, warn, info, debug or trace (LINKS_LOG_LEVEL, default info)
+  --journal-mode <mode>  wal, delete, truncate, persist, memory or off (LINKS_JOURNAL_MODE)
+  --synchronous <mode>   off, normal, full or extra (LINKS_SYNCHRONOUS)
+  --foreign-keys <bool>  enforce foreign keys (LINKS_FOREIGN_KEYS)
+  --trackers <list>      comma separated query parameters to strip from urls,
+                         on top of utm_*, fbclid, gclid and friends (LINKS_TRACKERS)
+  --allowed-schemes <list>
+                         comma separated url schemes links may use
+                         (LINKS_ALLOWED_SCHEMES, default http,https,mailto,ftp,gopher,gemini)
+  --max-url-length <n>   longest url accepted (LINKS_MAX_URL_LENGTH, default 2048)
+  --workers <n>          background jobs run at once (LINKS_WORKERS, default 2)
+  --block-private-addresses <bool>
+                         refuse to fetch loopback, private and link local
+                         addresses in the background (LINKS_BLOCK_PRIVATE_ADDRESSES,
+                         default true)
+
+commands:
+  --pending              print migrations that have not run yet
+  --rollback <version>   undo migrations newer than <version>
+  --create-admin <email> create the admin account, or claim the one holding
+                         links saved before accounts existed; reads the
+                         password from stdin
+
+flags beat environment variables, which beat the config file.";
+
+/// Everything that can be set with a flag or a `LINKS_*` variable.
+const SETTINGS: &[&str] = &[
+    "config",
+    "listen",
+    "database",
+    "base_url",
+    "log_level",
+    "trackers",
+    "allowed_schemes",
+    "max_url_length",
+    "workers",
+    "block_private_addresses",
+    "journal_mode",
+    "synchronous",
+    "foreign_keys",
+];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub database: PathBuf,
+    pub base_url: String,
+    pub log_level: String,
+    pub trackers: Vec<String>,
+    pub allowed_schemes: Vec<String>,
+    pub max_url_length: usize,
+    pub workers: usize,
+    pub block_private_addresses: bool,
+    pub sqlite: Sqlite,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sqlite {
+    pub journal_mode: String,
+    pub synchronous: String,
+    pub foreign_keys: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 9007)),
+            database: PathBuf::from("db.sqlite3"),
+            base_url: "http://localhost:9007".into(),
+            log_level: "info".into(),
+            trackers: vec![],
+            allowed_schemes: ["http", "https", "mailto", "ftp", "gopher", "gemini"]
+                .map(String::from)
+                .to_vec(),
+            max_url_length: 2048,
+            workers: 2,
+            block_private_addresses: true,
+            sqlite: Sqlite::default(),
+        }
+    }
+}
+
+impl Default for Sqlite {
+    fn default() -> Self {
+        Self {
+            journal_mode: "wal".into(),
+            synchronous: "normal".into(),
+            foreign_keys: true,
+        }
+    }
+}
+
+pub enum Command {
+    Serve,
+    Pending,
+    Rollback(i64),
+    CreateAdmin(String),
+}
+
+impl Config {
+    /// Builds the config from the file, the environment variables in `env`
+    /// and `args` (without the program name) and returns the command to run.
+    pub fn load(
+        args: impl IntoIterator<Item = String>,
+        env: &HashMap<String, String>,
+    ) -> Res<(Self, Command)> {
+        let mut flags = vec![];
+        let mut command = Command::Serve;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--pending" => command = Command::Pending,
+                "--rollback" => {
+                    let version = args
+                        .next()
+                        .and_then(|version| version.parse().ok())
+                        .ok_or(usage("--rollback needs a version"))?;
+                    command = Command::Rollback(version);
+                }
+                "--create-admin" => {
+                    let email = args.next().ok_or(usage("--create-admin needs an email"))?;
+                    command = Command::CreateAdmin(email);
+                }
+                "-h" | "--help" => return Err(usage("")),
+                flag if flag.starts_with("--") => {
+                    let name = flag.trim_start_matches("--").replace('-', "_");
+                    if !SETTINGS.contains(&name.as_str()) {
+                        return Err(usage(&format!("unknown option {}", flag)));
+                    }
+                    let value = args
+                        .next()
+                        .ok_or(usage(&format!("{} needs a value", flag)))?;
+                    flags.push((name, value));
+                }
+                other => return Err(usage(&format!("unknown argument {}", other))),
+            }
+        }
+
+        let flag = |name: &str| {
+            flags
+                .iter()
+                .rev()
+                .find(|(flag, _)| flag == name)
+                .map(|(_, value)| value.clone())
+        };
+        let setting = |name: &str| {
+            flag(name).or_else(|| env.get(&format!("LINKS_{}", name.to_uppercase())).cloned())
+        };
+
+        let mut config = match setting("config") {
+            Some(path) => Self::from_file(&path)?,
+            None if std::path::Path::new("links.toml").exists() => Self::from_file("links.toml")?,
+            None => Self::default(),
+        };
+        if let Some(listen) = setting("listen") {
+            config.listen = listen
+                .parse()
+                .map_err(|_| usage(&format!("invalid listen address {}", listen)))?;
+        }
+        if let Some(database) = setting("database") {
+            config.database = database.into();
+        }
+        if let Some(base_url) = setting("base_url") {
+            config.base_url = base_url;
+        }
+        if let Some(log_level) = setting("log_level") {
+            config.log_level = log_level;
+        }
+        if let Some(trackers) = setting("trackers") {
+            config.trackers = list(&trackers);
+        }
+        if let Some(schemes) = setting("allowed_schemes") {
+            config.allowed_schemes = list(&schemes);
+        }
+        if let Some(max_url_length) = setting("max_url_length") {
+            config.max_url_length = max_url_length
+                .parse()
+                .map_err(|_| usage("max_url_length must be a number"))?;
+        }
+        if let Some(workers) = setting("workers") {
+            config.workers = workers
+                .parse()
+                .map_err(|_| usage("workers must be a number"))?;
+        }
+        if let Some(block) = setting("block_private_addresses") {
+            config.block_private_addresses = block
+                .parse()
+                .map_err(|_| usage("block_private_addresses must be true or false"))?;
+        }
+        if let Some(journal_mode) = setting("journal_mode") {
+            config.sqlite.journal_mode = journal_mode;
+        }
+        if let Some(synchronous) = setting("synchronous") {
+            config.sqlite.synchronous = synchronous;
+        }
+        if let Some(foreign_keys) = setting("foreign_keys") {
+            config.sqlite.foreign_keys = foreign_keys
+                .parse()
+                .map_err(|_| usage("foreign_keys must be true or false"))?;
+        }
+        config.base_url = config.base_url.trim_end_matches('/').to_owned();
+        let _journal_mode = config.sqlite.journal_mode()?;
+        let _synchronous = config.sqlite.synchronous()?;
+
+        Ok((config, command))
+    }
+
+    fn from_file(path: &str) -> Res<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| usage(&format!("reading {}: {}", path, err)))?;
+        toml::from_str(&contents).map_err(|err| usage(&format!("parsing {}: {}", path, err)))
+    }
+}
+
+impl Sqlite {
+    pub fn journal_mode(&self) -> Res<rizz::JournalMode> {
+        match self.journal_mode.to_lowercase().as_str() {
+            "wal" => Ok(rizz::JournalMode::Wal),
+            "delete" => Ok(rizz::JournalMode::Delete),
+            "truncate" => Ok(rizz::JournalMode::Truncate),
+            "persist" => Ok(rizz::JournalMode::Persist),
+            "memory" => Ok(rizz::JournalMode::Memory),
+            "off" => Ok(rizz::JournalMode::Off),
+            other => Err(usage(&format!("unknown journal_mode {}", other))),
+        }
+    }
+
+    pub fn synchronous(&self) -> Res<rizz::Synchronous> {
+        match self.synchronous.to_lowercase().as_str() {
+            "off" => Ok(rizz::Synchronous::Off),
+            "normal" => Ok(rizz::Synchronous::Normal),
+            "full" => Ok(rizz::Synchronous::Full),
+            "extra" => Ok(rizz::Synchronous::Extra),
+            other => Err(usage(&format!("unknown synchronous {}", other))),
+        }
+    }
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn usage(message: &str) -> Error {
+    match message.is_empty() {
+        true => Error::BadRequest(USAGE.into()),
+        false => Error::BadRequest(format!("{}\n\n{}", message, USAGE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> Res<(Config, Command)> {
+        load_with_env(args, &[])
+    }
+
+    fn load_with_env(args: &[&str], env: &[(&str, &str)]) -> Res<(Config, Command)> {
+        let env = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::load(args.iter().map(|arg| arg.to_string()), &env)
+    }
+
+    /// Writes a config file only this test reads.
+    fn config_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("links-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn flags_beat_environment_beats_file() {
+        let path = config_file(
+            "precedence",
+            r#"
+            base_url = "https://links.example/"
+            log_level = "warn"
+
+            [sqlite]
+            journal_mode = "delete"
+            synchronous = "full"
+            "#,
+        );
+        let loaded = load_with_env(
+            &["--config", &path, "--synchronous", "extra", "--pending"],
+            &[("LINKS_LOG_LEVEL", "debug"), ("LINKS_SYNCHRONOUS", "off")],
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let (config, command) = loaded.unwrap();
+        assert_eq!(config.sqlite.synchronous, "extra");
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.base_url, "https://links.example");
+        assert_eq!(config.sqlite.journal_mode, "delete");
+        assert!(config.sqlite.foreign_keys);
+        assert_eq!(config.listen, Config::default().listen);
+        assert!(matches!(command, Command::Pending));
+    }
+
+    #[test]
+    fn reads_commands() {
+        let path = config_file("commands", "");
+        let command = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["--config", &path]);
+            load(&args).map(|(_, command)| command)
+        };
+        assert!(matches!(command(&[]), Ok(Command::Serve)));
+        assert!(matches!(
+            command(&["--rollback", "3"]),
+            Ok(Command::Rollback(3))
+        ));
+        assert!(matches!(
+            command(&["--create-admin", "admin@example.com"]),
+            Ok(Command::CreateAdmin(email)) if email == "admin@example.com"
+        ));
+        assert!(command(&["--rollback"]).is_err());
+        assert!(command(&["--rollback", "latest"]).is_err());
+        assert!(command(&["serve"]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let unknown = config_file("unknown", "lisen = \"0.0.0.0:80\"");
+        let journal = config_file("journal", "[sqlite]\njournal_mode = \"fast\"");
+        for args in [
+            vec!["--config", &unknown],
+            vec!["--config", &journal],
+            vec![
+                "--config",
+                &journal,
+                "--journal-mode",
+                "wal",
+                "--listen",
+                "9007",
+            ],
+            vec![
+                "--config",
+                &journal,
+                "--journal-mode",
+                "wal",
+                "--foreign-keys",
+                "yes",
+            ],
+        ] {
+            assert!(
+                matches!(load(&args), Err(Error::BadRequest(_))),
+                "{:?}",
+                args
+            );
+        }
+        assert!(load(&["--config", &journal, "--journal-mode", "wal"]).is_ok());
+        std::fs::remove_file(&unknown).unwrap();
+        std::fs::remove_file(&journal).unwrap();
+        assert!(load(&["--config", &unknown]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let path = config_file("flags", "");
+        let loaded = load(&["--config", &path, "--wrokers", "2"]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            loaded,
+            Err(Error::BadRequest(message)) if message.starts_with("unknown option --wrokers") && message.ends_with(USAGE)
+        ));
+    }
+}
@@ -0,0 +1,490 @@
+//! A durable job queue in sqlite for the slow work done after a link is
+//! saved: fetching metadata, archiving and article extraction, plus the
+//! periodic dead link checks.
+//!
+//! Workers claim a job by taking a lease on it. A job whose lease runs out,
+//! say because the server stopped mid-run, goes back to whoever claims
+//! next. Failed jobs are retried with exponential backoff until they run
+//! out of attempts, then kept around as failed so they can be retried by
+//! hand from `/jobs`.
+
+use std::{sync::OnceLock, time::Duration};
+
+use axum::{
+    extract::Path,
+    response::Redirect,
+    routing::{get, post},
+    Router,
+};
+use maud::{html, Markup};
+use rizz::Database;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{auth::User, button, date, fetcher, now, Component, Context, Error, Html, Links, Res};
+
+/// How long a worker may hold a job before it's presumed dead.
+const LEASE: f64 = 10.0 * 60.0;
+
+const MAX_ATTEMPTS: i64 = 5;
+
+/// The first retry waits this long, doubling with each attempt after.
+const BACKOFF: f64 = 30.0;
+
+const MAX_BACKOFF: f64 = 60.0 * 60.0;
+
+/// How often idle workers look for jobs nobody told them about, like ones
+/// whose backoff or lease just ran out.
+const POLL: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub enum JobsRoute {
+    Jobs,
+    Retry,
+}
+
+impl std::fmt::Display for JobsRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let x: &str = self.to_owned().into();
+        f.write_str(x)
+    }
+}
+
+impl From<JobsRoute> for &'static str {
+    fn from(value: JobsRoute) -> Self {
+        match value {
+            JobsRoute::Jobs => "/jobs",
+            JobsRoute::Retry => "/jobs/:id/retry",
+        }
+    }
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route(JobsRoute::Jobs.into(), get(jobs))
+        .route(JobsRoute::Retry.into(), post(retry))
+}
+
+fn retry_url(id: &str) -> String {
+    let route: &str = JobsRoute::Retry.into();
+    route.replace(":id", id)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Metadata,
+    Archive,
+    Read,
+    Check,
+}
+
+impl JobKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Metadata => "metadata",
+            JobKind::Archive => "archive",
+            JobKind::Read => "read",
+            JobKind::Check => "check",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Failed,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Job {
+    pub id: String,
+    pub user_id: String,
+    pub kind: JobKind,
+    pub link_id: String,
+    pub status: JobStatus,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub created_at: f64,
+    /// The link's url, joined in for display.
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+static WAKE: OnceLock<Notify> = OnceLock::new();
+
+fn wake() -> &'static Notify {
+    WAKE.get_or_init(Notify::new)
+}
+
+/// Starts `count` workers pulling jobs off the queue.
+pub fn start(db: Database, count: usize) {
+    for worker in 0..count {
+        let db = db.clone();
+        tokio::spawn(async move {
+            log::info!("job worker {} started", worker);
+            loop {
+                match claim(&db).await {
+                    Ok(Some(job)) => finish(&db, &job, run(&db, &job).await).await,
+                    Ok(None) => {
+                        let _timeout = tokio::time::timeout(POLL, wake().notified()).await;
+                    }
+                    Err(err) => {
+                        log::error!("job worker {} could not claim a job: {:?}", worker, err);
+                        tokio::time::sleep(POLL).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Takes the next job that's due, or one whose lease ran out.
+async fn claim(db: &Database) -> Res<Option<Job>> {
+    let now = now();
+    let rows: Vec<Job> = db
+        .query(
+            "update jobs set status = 'running', attempts = attempts + 1, locked_until = ?, updated_at = ?
+             where id = (
+                 select id from jobs
+                 where (status = 'queued' and run_at <= ?) or (status = 'running' and locked_until < ?)
+                 order by run_at
+                 limit 1
+             )
+             returning *",
+            vec![
+                (now + LEASE).into(),
+                now.into(),
+                now.into(),
+                now.into(),
+            ],
+        )
+        .all()
+        .await?;
+    Ok(rows.into_iter().next())
+}
+
+async fn run(db: &Database, job: &Job) -> Res<()> {
+    let users: Vec<User> = db
+        .query(
+            "select * from users where id = ?",
+            vec![job.user_id.clone().into()],
+        )
+        .all()
+        .await?;
+    let user = users.into_iter().next().ok_or(Error::NotFound)?;
+    let cx = Context {
+        db: db.clone(),
+        links: Links::new(),
+        fetcher: fetcher(),
+        htmx: false,
+        json: false,
+        user,
+        token: None,
+    };
+    let link = cx.link(&job.link_id).await?;
+    // queued before non-web links were left out; nothing to do for them
+    if !link.is_fetchable() {
+        return Ok(());
+    }
+    match job.kind {
+        JobKind::Metadata => cx.capture_metadata(&link.id, &link.url).await,
+        JobKind::Archive => cx.archive_link(&link.id, &link.url).await,
+        JobKind::Read => cx.read_link(&link.id, &link.url).await.map(|_| ()),
+        JobKind::Check => cx.check_link(&link).await,
+    }
+}
+
+/// Done jobs are deleted. Failed ones go back in the queue after a backoff,
+/// or stay failed once they've used up their attempts.
+async fn finish(db: &Database, job: &Job, result: Res<()>) {
+    let result = match result {
+        Ok(()) => {
+            db.execute("delete from jobs where id = ?", vec![job.id.clone().into()])
+                .await
+        }
+        Err(err) => {
+            let message = format!("{:?}", err);
+            log::warn!(
+                "{} job {} failed on attempt {}: {}",
+                job.kind.as_str(),
+                job.id,
+                job.attempts,
+                message
+            );
+            let status = match job.attempts >= MAX_ATTEMPTS {
+                true => "failed",
+                false => "queued",
+            };
+            let delay = (BACKOFF * 2f64.powi(job.attempts as i32 - 1)).min(MAX_BACKOFF);
+            db.execute(
+                "update jobs set status = ?, run_at = ?, locked_until = null, last_error = ?, updated_at = ? where id = ?",
+                vec![
+                    status.into(),
+                    (now() + delay).into(),
+                    message.into(),
+                    now().into(),
+                    job.id.clone().into(),
+                ],
+            )
+            .await
+        }
+    };
+    if let Err(err) = result {
+        log::error!("could not record the result of job {}: {:?}", job.id, err);
+    }
+}
+
+impl Context {
+    /// Queues a job for a link, unless the same one is already waiting.
+    pub async fn enqueue(&self, kind: JobKind, link_id: &str) -> Res<()> {
+        let now = now();
+        let _rows_affected = self
+            .db
+            .execute(
+                "insert into jobs (id, user_id, kind, link_id, status, attempts, run_at, created_at, updated_at)
+                 select ?, ?, ?, ?, 'queued', 0, ?, ?, ?
+                 where not exists (
+                     select 1 from jobs where kind = ? and link_id = ? and status in ('queued', 'running')
+                 )",
+                vec![
+                    nanoid::nanoid!().into(),
+                    self.user.id.clone().into(),
+                    kind.as_str().into(),
+                    link_id.into(),
+                    now.into(),
+                    now.into(),
+                    now.into(),
+                    kind.as_str().into(),
+                    link_id.into(),
+                ],
+            )
+            .await?;
+        wake().notify_one();
+        Ok(())
+    }
+
+    async fn jobs(&self) -> Res<Vec<Job>> {
+        let rows = self
+            .db
+            .query(
+                "select jobs.*, links.url from jobs
+                 left join links on links.id = jobs.link_id
+                 where jobs.user_id = ?
+                 order by jobs.run_at",
+                vec![self.user.id.clone().into()],
+            )
+            .all()
+            .await?;
+        Ok(rows)
+    }
+}
+
+struct JobsComponent {
+    jobs: Vec<Job>,
+}
+
+impl Component for JobsComponent {
+    fn html(&self) -> Markup {
+        let sections = [
+            (JobStatus::Running, "Running"),
+            (JobStatus::Queued, "Queued"),
+            (JobStatus::Failed, "Failed"),
+        ];
+        html! {
+            @for (status, heading) in sections {
+                @let jobs: Vec<&Job> = self.jobs.iter().filter(|job| job.status == status).collect();
+                h2 class="text-xl" { (heading) " (" (jobs.len()) ")" }
+                @if jobs.is_empty() {
+                    p class="text-gray-500" { "Nothing here." }
+                }
+                div class="w-full flex flex-col gap-4 divide-y dark:divide-gray-700 divide-gray-200" {
+                    @for job in jobs {
+                        div class="flex justify-between items-center gap-3 pt-4" {
+                            div class="flex flex-col min-w-0" {
+                                span class="text-lg" { (job.kind.as_str()) }
+                                span class="text-sm text-gray-500 truncate" { (job.url.as_deref().unwrap_or(&job.link_id)) }
+                                span class="text-sm text-gray-500" {
+                                    "queued " (date(job.created_at)) " · " (job.attempts) " of " (MAX_ATTEMPTS) " attempts"
+                                }
+                                @if let Some(error) = &job.last_error {
+                                    span class="text-sm text-red-500 break-all" { (error) }
+                                }
+                            }
+                            @if job.status == JobStatus::Failed {
+                                form action=(retry_url(&job.id)) method="post" {
+                                    (button("Retry"))
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn jobs(cx: Context) -> Html {
+    let jobs = cx.jobs().await?;
+    cx.render(JobsComponent { jobs })
+}
+
+async fn retry(cx: Context, Path(id): Path<String>) -> Res<Redirect> {
+    let rows_affected = cx
+        .db
+        .execute(
+            "update jobs set status = 'queued', attempts = 0, run_at = ?, locked_until = null, updated_at = ?
+             where id = ? and user_id = ? and status = 'failed'",
+            vec![
+                now().into(),
+                now().into(),
+                id.into(),
+                cx.user.id.clone().into(),
+            ],
+        )
+        .await?;
+    if rows_affected == 0 {
+        return Err(Error::NotFound);
+    }
+    wake().notify_one();
+    Ok(Redirect::to(JobsRoute::Jobs.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{memory_db, signed_in, Stub},
+        Link,
+    };
+
+    #[derive(Deserialize)]
+    struct Schedule {
+        status: JobStatus,
+        run_at: f64,
+        locked_until: Option<f64>,
+        last_error: Option<String>,
+    }
+
+    async fn schedule(db: &Database, id: &str) -> Schedule {
+        let rows: Vec<Schedule> = db
+            .query(
+                "select status, run_at, locked_until, last_error from jobs where id = ?",
+                vec![id.into()],
+            )
+            .all()
+            .await
+            .unwrap();
+        rows.into_iter().next().unwrap()
+    }
+
+    /// Queues a metadata job that always fails, since fetches from this
+    /// machine to itself are refused.
+    async fn failing_job(cx: &Context) {
+        let link = Link::new(&cx.user.id, "http://127.0.0.1:9/".into());
+        let link_id = link.id.clone();
+        let _rows_affected = cx
+            .db
+            .insert_into(cx.links)
+            .values(link)
+            .unwrap()
+            .rows_affected()
+            .await
+            .unwrap();
+        cx.enqueue(JobKind::Metadata, &link_id).await.unwrap();
+    }
+
+    /// Moves every queued job's backoff into the past.
+    async fn make_due(db: &Database) {
+        let _rows_affected = db
+            .execute("update jobs set run_at = 0 where status = 'queued'", vec![])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn claims_a_job_with_a_lease() {
+        let db = memory_db().await;
+        let cx = signed_in(&db, Stub::default()).await;
+        failing_job(&cx).await;
+
+        let before = now();
+        let job = claim(&db).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Running);
+        assert_eq!(job.attempts, 1);
+        let locked_until = schedule(&db, &job.id).await.locked_until.unwrap();
+        assert!(locked_until >= before + LEASE && locked_until <= now() + LEASE);
+        // nobody else gets it while the lease holds
+        assert!(claim(&db).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reclaims_a_job_whose_lease_ran_out() {
+        let db = memory_db().await;
+        let cx = signed_in(&db, Stub::default()).await;
+        failing_job(&cx).await;
+        let job = claim(&db).await.unwrap().unwrap();
+
+        let _rows_affected = db
+            .execute(
+                "update jobs set locked_until = ? where id = ?",
+                vec![(now() - 1.0).into(), job.id.clone().into()],
+            )
+            .await
+            .unwrap();
+        let reclaimed = claim(&db).await.unwrap().unwrap();
+        assert_eq!(reclaimed.id, job.id);
+        assert_eq!(reclaimed.attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn backs_off_then_gives_up_on_a_failing_job() {
+        let db = memory_db().await;
+        let cx = signed_in(&db, Stub::default()).await;
+        failing_job(&cx).await;
+
+        let mut delays = vec![];
+        for attempt in 1..=MAX_ATTEMPTS {
+            make_due(&db).await;
+            let job = claim(&db).await.unwrap().unwrap();
+            assert_eq!(job.attempts, attempt);
+            let result = run(&db, &job).await;
+            assert!(result.is_err());
+            let before = now();
+            finish(&db, &job, result).await;
+
+            let schedule = schedule(&db, &job.id).await;
+            assert!(schedule.locked_until.is_none());
+            assert!(schedule.last_error.is_some());
+            match attempt < MAX_ATTEMPTS {
+                true => {
+                    assert_eq!(schedule.status, JobStatus::Queued);
+                    delays.push((schedule.run_at - before).round());
+                    // not due again until the backoff is over
+                    assert!(claim(&db).await.unwrap().is_none());
+                }
+                false => assert_eq!(schedule.status, JobStatus::Failed),
+            }
+        }
+        assert_eq!(
+            delays,
+            vec![BACKOFF, BACKOFF * 2.0, BACKOFF * 4.0, BACKOFF * 8.0]
+        );
+        make_due(&db).await;
+        assert!(claim(&db).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn deletes_jobs_that_succeed() {
+        let db = memory_db().await;
+        let cx = signed_in(&db, Stub::default()).await;
+        failing_job(&cx).await;
+        let job = claim(&db).await.unwrap().unwrap();
+
+        finish(&db, &job, Ok(())).await;
+        let rows: Vec<Job> = db.query("select * from jobs", vec![]).all().await.unwrap();
+        assert!(rows.is_empty());
+    }
+}
@@ -1,23 +1,91 @@
-use std::sync::OnceLock;
+mod api;
+mod archive;
+mod auth;
+mod bookmarks;
+mod checker;
+mod config;
+mod jobs;
+mod migrations;
+mod normalize;
+mod pinboard;
+mod reader;
+mod settings;
+mod warc;
 
+use std::{
+    net::IpAddr,
+    sync::{Arc, OnceLock},
+};
+
+use auth::{ApiToken, Scope, User};
 use axum::{
     async_trait,
-    extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
-    response::{IntoResponse, Redirect},
-    routing::get,
-    Json, Router, Server,
+    extract::{DefaultBodyLimit, FromRequestParts, Multipart, Path, Query},
+    http::{header, request::Parts, HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, put},
+    Form, Json, Router, Server,
 };
+use config::{Command, Config};
+use jobs::JobKind;
 use maud::{html, Markup, DOCTYPE};
-use rizz::{desc, Connection, Database, Real, Table, Text};
+use migrations::{migrate, pending_migrations, rollback};
+use rizz::{and, desc, eq, Connection, Database, Integer, Real, Table, Text};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[tokio::main]
 async fn main() -> Res<()> {
+    let env: HashMap<String, String> = std::env::vars().collect();
+    let (loaded, command) = match Config::load(std::env::args().skip(1), &env) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}", err.message());
+            std::process::exit(2);
+        }
+    };
+    env_logger::Builder::new()
+        .parse_filters(&loaded.log_level)
+        .init();
+    let _x = CONFIG.set(loaded);
     let db = database().await?;
+    match command {
+        Command::Pending => {
+            for migration in pending_migrations(&db).await? {
+                println!("{:04} {}", migration.version, migration.name);
+            }
+            return Ok(());
+        }
+        Command::Rollback(version) => {
+            rollback(&db, version).await?;
+            return Ok(());
+        }
+        Command::CreateAdmin(email) => {
+            migrate(&db).await?;
+            eprint!("password for {}: ", email);
+            let mut password = String::new();
+            let _bytes = std::io::stdin().read_line(&mut password)?;
+            auth::create_admin(
+                &db,
+                &email,
+                password.trim_end_matches(['\r', '\n']).to_owned(),
+            )
+            .await?;
+            println!("{} can log in now", email);
+            return Ok(());
+        }
+        Command::Serve => {}
+    }
     migrate(&db).await?;
-    let addr: std::net::SocketAddr = "127.0.0.1:9007".parse().expect("addr not parsed");
-    println!("Listening on localhost:9007");
+    if auth::unclaimed_admin(&db).await?.is_some() {
+        log::warn!("links saved before accounts existed are waiting for an admin; run links --create-admin <email> to claim them");
+    }
+    normalize_existing(&db).await?;
+    jobs::start(db.clone(), config().workers);
+    checker::start(db.clone());
+    let addr = config().listen;
+    log::info!("Listening on {}", addr);
     Server::bind(&addr)
         .serve(routes().into_make_service())
         .await
@@ -26,12 +94,98 @@ async fn main() -> Res<()> {
     Ok(())
 }
 
-#[derive(Clone)]
+#[derive(Deserialize)]
+struct UnnormalizedLink {
+    id: String,
+    url: String,
+}
+
+/// Fills in `normalized_url` for links saved before it existed. Links that
+/// turn out to duplicate another one are left alone and logged.
+async fn normalize_existing(db: &Database) -> Res<()> {
+    let rows: Vec<UnnormalizedLink> = db
+        .query(
+            "select id, url from links where normalized_url is null",
+            vec![],
+        )
+        .all()
+        .await?;
+    for row in rows {
+        let normalized = normalize_url(&row.url);
+        let result = db
+            .execute(
+                "update links set normalized_url = ? where id = ?",
+                vec![normalized.into(), row.id.into()],
+            )
+            .await;
+        if let Err(err) = result {
+            log::warn!("not normalizing {}: {:?}", row.url, Error::from(err));
+        }
+    }
+    Ok(())
+}
+
+fn normalize_url(url: &str) -> String {
+    normalize::normalize(url, &config().trackers).unwrap_or_else(|| url.to_owned())
+}
+
+/// Browser exports carry every favicon inline, so a few thousand bookmarks
+/// is well past axum's default 2MB body limit.
+const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[derive(Clone, PartialEq)]
 enum Route {
     Home,
+    Unread,
+    Archive,
+    Tag,
+    Search,
+    Link,
+    EditLink,
+    LinkStatus,
+    Import,
+    Export,
+    QuickAdd,
+    Share,
+    Manifest,
+    ServiceWorker,
     File,
 }
 
+impl Route {
+    fn path(&self, param: &str) -> String {
+        let route: &str = self.clone().into();
+        let param = path_segment(param);
+        match route.split_once(':') {
+            Some((prefix, rest)) => match rest.split_once('/') {
+                Some((_, suffix)) => format!("{}{}/{}", prefix, param, suffix),
+                None => format!("{}{}", prefix, param),
+            },
+            None => route.to_owned(),
+        }
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, so a tag like
+/// `read/watch` stays one path segment.
+fn path_segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
 impl std::fmt::Display for Route {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let x: &str = self.to_owned().into();
@@ -43,40 +197,199 @@ impl From<Route> for &'static str {
     fn from(value: Route) -> Self {
         match &value {
             Route::Home => "/",
+            Route::Unread => "/unread",
+            Route::Archive => "/archive",
+            Route::Tag => "/tags/:name",
+            Route::Search => "/search",
+            Route::Link => "/links/:id",
+            Route::EditLink => "/links/:id/edit",
+            Route::LinkStatus => "/links/:id/status",
+            Route::Import => "/import",
+            Route::Export => "/export.html",
+            Route::QuickAdd => "/add",
+            Route::Share => "/share",
+            Route::Manifest => "/manifest.webmanifest",
+            Route::ServiceWorker => "/sw.js",
             Route::File => "/pub/*file",
         }
     }
 }
 
 fn routes() -> Router {
-    let handlers = Router::new().route(Route::Home.into(), get(home).post(add_link));
-    let assets = Router::new().route(Route::File.into(), get(files));
+    let handlers = Router::new()
+        .route(Route::Home.into(), get(home).post(add_link))
+        .route(Route::Unread.into(), get(unread))
+        .route(Route::Archive.into(), get(archive))
+        .route(Route::Tag.into(), get(tag))
+        .route(Route::Search.into(), get(search))
+        .route(
+            Route::Link.into(),
+            get(link).put(update_link).delete(delete_link),
+        )
+        .route(Route::EditLink.into(), get(edit_link))
+        .route(Route::LinkStatus.into(), put(update_status))
+        .route(
+            Route::Import.into(),
+            get(import)
+                .post(import_bookmarks)
+                .layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES)),
+        )
+        .route(Route::Export.into(), get(export))
+        .route(Route::QuickAdd.into(), get(quick_add).post(save_quick_add))
+        .route(Route::Share.into(), get(share_form).post(share));
+    let assets = Router::new()
+        .route(Route::File.into(), get(files))
+        .route(Route::Manifest.into(), get(files))
+        .route(Route::ServiceWorker.into(), get(files));
 
     Router::new()
         .nest("", handlers)
+        .nest("", api::routes())
+        .nest("", archive::routes())
+        .nest("", auth::routes())
+        .nest("", checker::routes())
+        .nest("", jobs::routes())
+        .nest("", settings::routes())
+        .nest("", pinboard::routes())
+        .nest("", reader::routes())
         .nest("", assets)
         .fallback(not_found)
+        .layer(middleware::from_fn(error_pages))
+}
+
+/// Swaps the plain body of error responses for an html page, or a json body
+/// when the client asked for json.
+async fn error_pages<B>(headers: HeaderMap, request: Request<B>, next: Next<B>) -> Response {
+    let api = request.uri().path().starts_with("/api/");
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.to_string())
+        .unwrap_or_default();
+    let response = next.run(request).await;
+    let Some(err) = response.extensions().get::<Error>().cloned() else {
+        return response;
+    };
+    let status = err.status();
+    if api || accepts_json(&headers) {
+        return (status, Json(err.body())).into_response();
+    }
+    if let Error::Unauthorized = err {
+        let login = auth::login_url(&path);
+        return match is_htmx(&headers) {
+            true => ([("hx-redirect", login)], StatusCode::UNAUTHORIZED).into_response(),
+            false => Redirect::to(&login).into_response(),
+        };
+    }
+    let component = ErrorComponent {
+        status,
+        message: err.message(),
+    };
+    match is_htmx(&headers) {
+        true => (status, component.html()).into_response(),
+        false => (status, layout(component, None)).into_response(),
+    }
+}
+
+fn is_htmx(headers: &HeaderMap) -> bool {
+    let header = |name: &str| headers.get(name).is_some_and(|value| value == "true");
+    header("hx-request") && !header("hx-boosted")
+}
+
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+struct ErrorComponent {
+    status: StatusCode,
+    message: String,
+}
+
+impl Component for ErrorComponent {
+    fn html(&self) -> Markup {
+        html! {
+            div class="flex flex-col items-center gap-3 py-12 text-center" {
+                p class="text-6xl font-bold text-orange-500" { (self.status.as_u16()) }
+                p class="text-xl" { (self.message) }
+                a class="text-sky-500 underline hover:text-sky-300" href=(Route::Home) { "back to links" }
+            }
+        }
+    }
 }
 
 struct HomeComponent {
-    error: Option<&'static str>,
-    links: Vec<Link>,
+    route: Route,
+    url: String,
+    url_error: Option<String>,
+    duplicate: Option<(Link, String)>,
+    page: Page<Link>,
+    tags: HashMap<String, Vec<String>>,
+    /// How many links look dead.
+    broken: i64,
 }
 
 impl Component for HomeComponent {
     fn html(&self) -> Markup {
         html! {
+            (search_form(""))
             form class="flex flex-col w-full gap-3" action=(Route::Home) method="post" {
-                (text_input("url"))
+                (text_input("url", &self.url, self.url_error.as_deref()))
+                (text_field("tags", "tags, separated by commas"))
                 (button("Add link"))
             }
-            @if let Some(err) = &self.error {
-                (err)
+            @if let Some((link, tags)) = &self.duplicate {
+                div class="flex flex-col gap-2 p-3 rounded-md bg-orange-100 dark:bg-orange-900" {
+                    p {
+                        a class="text-sky-500 underline hover:text-sky-300" href=(Route::Link.path(&link.id)) { (link.display_title()) }
+                        " is already saved on " (date(link.created_at))
+                    }
+                    form action=(Route::Home) method="post" {
+                        input type="hidden" name="url" value=(link.url);
+                        input type="hidden" name="tags" value=(tags);
+                        input type="hidden" name="bump" value="true";
+                        button type="submit" class="underline hover:text-orange-500" { "Move it to the top" }
+                    }
+                }
+            }
+            @if self.broken > 0 {
+                a class="p-3 rounded-md bg-red-100 dark:bg-red-900 hover:underline" href=(checker::BrokenRoute::Broken) {
+                    (self.broken) @if self.broken == 1 { " link looks broken" } @else { " links look broken" }
+                }
+            }
+            nav class="flex gap-4 border-b dark:border-gray-700 border-gray-200" {
+                @for (route, label) in [(Route::Unread, "Unread"), (Route::Archive, "Archive"), (Route::Home, "All")] {
+                    @let current = route == self.route;
+                    a class={ "pb-2 " @if current { "border-b-2 border-orange-500" } @else { "text-gray-500 hover:text-sky-500" } } href=(route) { (label) }
+                }
+            }
+            @if let Some(newer) = &self.page.newer {
+                a class="text-center text-sky-500 underline hover:text-sky-300" href=(page_url(&self.route, "after", newer)) { "newer" }
             }
-            div class="w-full flex flex-col gap-4 divide-y dark:divide-gray-700 divide-gray-200"  {
-                @for link in &self.links {
-                    a class="text-2xl text-sky-500 underline hover:text-sky-300" href=(link.url) {
-                        (link.url)
+            (LinksComponent { links: &self.page.items, tags: &self.tags, older: self.page.older.as_ref().map(|older| page_url(&self.route, "before", older)) }.html())
+        }
+    }
+}
+
+struct LinksComponent<'a> {
+    links: &'a [Link],
+    tags: &'a HashMap<String, Vec<String>>,
+    /// Url of the next page of older links, loaded as you scroll.
+    older: Option<String>,
+}
+
+impl<'a> Component for LinksComponent<'a> {
+    fn html(&self) -> Markup {
+        html! {
+            div id="links" class="w-full flex flex-col gap-4 divide-y dark:divide-gray-700 divide-gray-200"  {
+                @for link in self.links {
+                    (LinkRowComponent { link, tags: self.tags.get(&link.id) }.html())
+                }
+                @if let Some(older) = &self.older {
+                    a class="pt-4 text-center text-sky-500 underline hover:text-sky-300" href=(older) hx-get=(older) hx-trigger="click, revealed" hx-select="#links > *" hx-swap="outerHTML" {
+                        "older"
                     }
                 }
             }
@@ -84,226 +397,1946 @@ impl Component for HomeComponent {
     }
 }
 
-async fn home(cx: Context) -> Html {
-    let error = None;
-    let links = cx.links().await?;
-    let home = HomeComponent { error, links };
+struct LinkRowComponent<'a> {
+    link: &'a Link,
+    tags: Option<&'a Vec<String>>,
+}
 
-    cx.render(home)
+impl<'a> Component for LinkRowComponent<'a> {
+    fn html(&self) -> Markup {
+        let link = self.link;
+        html! {
+            div id=(link.dom_id()) class="flex flex-col gap-1 pt-4" {
+                div class="flex gap-3" {
+                    @if let Some(image) = &link.og_image {
+                        img class="w-16 h-16 object-cover rounded-md" src=(image) alt="" loading="lazy";
+                    }
+                    div class="flex flex-col gap-1 min-w-0" {
+                        a class="text-2xl text-sky-500 underline hover:text-sky-300 break-words" href=(link.href()) hx-boost="false" {
+                            (link.display_title())
+                        }
+                        span class="text-sm text-gray-500 truncate" { (link.url) }
+                        @if let Some(description) = &link.description {
+                            p class="text-gray-700 dark:text-gray-300" { (description) }
+                        }
+                        @if let Some(notes) = &link.notes {
+                            p class="text-gray-700 dark:text-gray-300 italic whitespace-pre-line" { (notes) }
+                        }
+                    }
+                }
+                @if let Some(tags) = self.tags {
+                    div class="flex flex-wrap gap-2" {
+                        @for tag in tags {
+                            (tag_chip(tag))
+                        }
+                    }
+                }
+                div class="flex gap-3 text-sm text-gray-500" {
+                    @if link.is_broken() {
+                        a class="text-red-500 hover:text-red-400" href=(checker::BrokenRoute::Broken) {
+                            "broken"
+                            @if let Some(status) = link.check_status {
+                                " (" (status) ")"
+                            }
+                        }
+                    }
+                    @if link.replaced_at.is_some() {
+                        span { "archived copy" }
+                    }
+                    @if let Some(read_at) = link.read_at {
+                        span { "read " (date(read_at)) }
+                    }
+                    @if link.is_fetchable() {
+                        a class="hover:text-sky-500" href=(reader::read_url(&link.id)) { "reader" }
+                    }
+                    @if link.captured_at.is_some() {
+                        a class="hover:text-sky-500" href=(archive::snapshot_url(&link.id)) hx-boost="false" { "offline copy" }
+                        a class="hover:text-sky-500" href=(archive::warc_url(&link.id)) hx-boost="false" { "warc" }
+                    }
+                    @for (status, label) in link.status.actions() {
+                        button type="button" class="hover:text-sky-500" hx-put=(Route::LinkStatus.path(&link.id)) hx-vals=(format!(r#"{{"status":"{}"}}"#, status.as_str())) hx-target=(format!("#{}", link.dom_id())) hx-swap="outerHTML" {
+                            (label)
+                        }
+                    }
+                    button type="button" class="hover:text-sky-500" hx-get=(Route::EditLink.path(&link.id)) hx-target=(format!("#{}", link.dom_id())) hx-swap="outerHTML" {
+                        "edit"
+                    }
+                    button type="button" class="hover:text-red-500" hx-delete=(Route::Link.path(&link.id)) hx-target=(format!("#{}", link.dom_id())) hx-swap="outerHTML" hx-confirm="Delete this link?" {
+                        "delete"
+                    }
+                }
+            }
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize)]
-struct LinkParams {
-    url: String,
+struct EditLinkComponent<'a> {
+    link: &'a Link,
+    error: Option<String>,
 }
 
-async fn add_link(cx: Context, Json(params): Json<LinkParams>) -> Res<impl IntoResponse> {
-    if !params.url.starts_with("https://") {
-        let links = cx.links().await?;
-        let error = Some("Url needs to start with https://".into());
-        let home = HomeComponent { error, links };
-        return Ok(cx.render(home).into_response());
+impl<'a> Component for EditLinkComponent<'a> {
+    fn html(&self) -> Markup {
+        let link = self.link;
+        html! {
+            form id=(link.dom_id()) class="flex flex-col gap-3 pt-4" hx-put=(Route::Link.path(&link.id)) hx-target="this" hx-swap="outerHTML" {
+                input type="text" class="p-2 text-lg bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="url" value=(link.url);
+                input type="text" class="p-2 text-lg bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="title" value=(link.title.as_deref().unwrap_or_default()) placeholder="title";
+                textarea class="p-2 text-lg bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="notes" placeholder="notes" {
+                    (link.notes.as_deref().unwrap_or_default())
+                }
+                @if let Some(err) = &self.error {
+                    (err)
+                }
+                div class="flex gap-3" {
+                    (button("Save"))
+                    button type="button" class="px-2 py-4 rounded-md hover:bg-gray-100 dark:hover:bg-gray-800" hx-get=(Route::Link.path(&link.id)) hx-target="closest form" hx-swap="outerHTML" {
+                        "Cancel"
+                    }
+                }
+            }
+        }
     }
-    let Context { db, links } = cx;
-    let _rows_affected = db
-        .insert_into(links)
-        .values(Link {
-            url: params.url,
-            created_at: now(),
-            id: nanoid::nanoid!(),
-        })?
-        .rows_affected()
-        .await?;
-    Ok(Redirect::to(Route::Home.into()).into_response())
 }
 
-async fn not_found() -> impl IntoResponse {
-    Error::NotFound
+struct TagComponent {
+    name: String,
+    links: Vec<Link>,
+    tags: HashMap<String, Vec<String>>,
 }
 
-async fn files(uri: axum::http::Uri) -> impl IntoResponse {
-    let mut path = uri.path().trim_start_matches('/').to_string();
-    if path.starts_with("pub/") {
-        path = path.replace("pub/", "");
+impl Component for TagComponent {
+    fn html(&self) -> Markup {
+        html! {
+            div class="flex justify-between items-center" {
+                h2 class="text-xl" { "#" (self.name) }
+                a class="text-sky-500 underline hover:text-sky-300" href=(Route::Home) { "all links" }
+            }
+            (LinksComponent { links: &self.links, tags: &self.tags, older: None }.html())
+        }
     }
-    StaticFile(path)
 }
 
-#[derive(rust_embed::RustEmbed)]
-#[folder = "pub"]
-pub struct Files;
+struct SearchComponent {
+    q: String,
+    results: Vec<SearchResult>,
+}
 
-pub struct StaticFile<T>(pub T);
+impl Component for SearchComponent {
+    fn html(&self) -> Markup {
+        html! {
+            (search_form(&self.q))
+            @if !self.q.trim().is_empty() && self.results.is_empty() {
+                p class="text-gray-500" { "No links match " (self.q) }
+            }
+            div class="w-full flex flex-col gap-4 divide-y dark:divide-gray-700 divide-gray-200" {
+                @for result in &self.results {
+                    div class="flex flex-col gap-1 pt-4" {
+                        a class="text-2xl text-sky-500 underline hover:text-sky-300 break-words" href=(result.link.url) {
+                            (result.link.display_title())
+                        }
+                        span class="text-sm text-gray-500 truncate" { (result.link.url) }
+                        p class="text-gray-700 dark:text-gray-300" { (highlight(&result.snippet)) }
+                    }
+                }
+            }
+        }
+    }
+}
 
-impl<T> StaticFile<T>
-where
-    T: Into<String>,
-{
-    fn maybe_response(self) -> Res<axum::response::Response> {
-        let path = self.0.into();
-        let asset = Files::get(path.as_str()).ok_or(Error::NotFound)?;
-        let body = axum::body::boxed(axum::body::Full::from(asset.data));
-        let mime = mime_guess::from_path(path).first_or_octet_stream();
-        let response = axum::response::Response::builder()
-            .header(axum::http::header::CONTENT_TYPE, mime.as_ref())
-            .header(axum::http::header::CACHE_CONTROL, "public, max-age=604800")
-            .body(body)
-            .map_err(|_| Error::NotFound)?;
-        Ok(response)
+async fn home(cx: Context, Query(cursor): Query<PageParams>) -> Res<Response> {
+    list(cx, Route::Home, cursor).await
+}
+
+async fn unread(cx: Context, Query(mut cursor): Query<PageParams>) -> Res<Response> {
+    cursor.status = Some(Status::Unread);
+    list(cx, Route::Unread, cursor).await
+}
+
+async fn archive(cx: Context, Query(mut cursor): Query<PageParams>) -> Res<Response> {
+    cursor.status = Some(Status::Archived);
+    list(cx, Route::Archive, cursor).await
+}
+
+async fn list(cx: Context, route: Route, cursor: PageParams) -> Res<Response> {
+    let page = cx.links(&cursor).await?;
+    if cx.json {
+        return Ok(Json(cx.json_page(page).await?).into_response());
     }
+    let tags = cx.tags_for(&page.items).await?;
+    let home = HomeComponent {
+        route,
+        url: String::new(),
+        url_error: None,
+        duplicate: None,
+        page,
+        tags,
+        broken: cx.broken_count().await?,
+    };
+
+    Ok(cx.render(home).into_response())
 }
 
-impl<T> IntoResponse for StaticFile<T>
-where
-    T: Into<String>,
-{
-    fn into_response(self) -> axum::response::Response {
-        self.maybe_response()
-            .unwrap_or(Error::NotFound.into_response())
+async fn tag(cx: Context, Path(name): Path<String>) -> Html {
+    let name = name.trim().to_lowercase();
+    let links = cx.tagged_links(&name).await?;
+    if links.is_empty() {
+        return Err(Error::NotFound);
     }
+    let tags = cx.tags_for(&links).await?;
+    let component = TagComponent { name, links, tags };
+
+    cx.render(component)
 }
 
-#[derive(Debug)]
-enum Error {
-    NotFound,
-    Database(String),
+async fn link(cx: Context, Path(id): Path<String>) -> Html {
+    let link = cx.link(&id).await?;
+    let tags = cx.tags_for(std::slice::from_ref(&link)).await?;
+    let row = LinkRowComponent {
+        link: &link,
+        tags: tags.get(&link.id),
+    };
+
+    cx.partial(row)
 }
 
-type Res<T> = Result<T, Error>;
-type Html = Res<Markup>;
+async fn edit_link(cx: Context, Path(id): Path<String>) -> Html {
+    let link = cx.link(&id).await?;
+    let component = EditLinkComponent {
+        link: &link,
+        error: None,
+    };
 
-#[derive(Clone)]
-struct Context {
-    db: Database,
-    links: Links,
+    cx.partial(component)
 }
 
-trait Component {
-    fn html(&self) -> Markup;
+#[derive(Deserialize)]
+struct UpdateLinkParams {
+    url: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    notes: String,
 }
 
-impl Context {
-    fn render(&self, component: impl Component) -> Html {
-        Ok(html! {
-            (DOCTYPE)
-            html lang="en" {
-                head {
-                    title { "links" }
-                    meta name="viewport" content="width=device-width, initial-scale=1";
-                    meta charset="UTF-8";
-                    link rel="stylesheet" href="./pub/tailwind.css";
-                    script src="./pub/htmx.org@1.9.5.js" {}
-                    script src="./pub/json-enc.js" {}
-                }
-                body class="bg-white dark:bg-gray-950 dark:text-white" hx-boost="true" hx-ext="json-enc" {
-                    div class="max-w-lg mx-auto w-full lg:px-0 px-3 h-screen flex flex-col gap-3" {
-                        h1 class="text-2xl lg:text-4xl text-center" { "links" }
-                        (component.html())
-                    }
-                }
-            }
-        })
+async fn update_link(
+    cx: Context,
+    Path(id): Path<String>,
+    Json(params): Json<UpdateLinkParams>,
+) -> Html {
+    let mut link = cx.link(&id).await?;
+    link.set_url(params.url.trim());
+    link.title = non_empty(params.title);
+    link.notes = non_empty(params.notes);
+    let error = match validate_url(&link.url) {
+        Err(err) => Some(err.message()),
+        Ok(()) => match cx.link_by_url(&link.url).await? {
+            Some(existing) if existing.id != link.id => Some(format!(
+                "{} is already saved on {}",
+                existing.url,
+                date(existing.created_at)
+            )),
+            _ => None,
+        },
+    };
+    if error.is_some() {
+        let component = EditLinkComponent { link: &link, error };
+        return cx.partial(component);
     }
+    cx.save_link(&link).await?;
+    let tags = cx.tags_for(std::slice::from_ref(&link)).await?;
+    let row = LinkRowComponent {
+        link: &link,
+        tags: tags.get(&link.id),
+    };
 
-    async fn links(&self) -> Res<Vec<Link>> {
-        let Context { db, links } = &self;
-        let rows = db
-            .select()
-            .from(*links)
-            .order(vec![(desc(links.created_at))])
-            .limit(10)
-            .all()
-            .await?;
-        Ok(rows)
-    }
+    cx.partial(row)
 }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> axum::response::Response {
-        match self {
-            Error::NotFound => (StatusCode::NOT_FOUND, "not found").into_response(),
-            Error::Database(_) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, "internal server error").into_response()
-            }
-        }
+async fn delete_link(cx: Context, Path(id): Path<String>) -> Res<impl IntoResponse> {
+    let link = cx.link(&id).await?;
+    cx.delete_link(&link.id).await?;
+    match cx.htmx {
+        true => Ok(html! {}.into_response()),
+        false => Ok(Redirect::to(Route::Home.into()).into_response()),
     }
 }
 
-#[async_trait]
-impl<S> FromRequestParts<S> for Context {
-    type Rejection = Error;
+#[derive(Deserialize)]
+struct StatusParams {
+    status: Status,
+}
 
-    async fn from_request_parts(_parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        Ok(Context {
-            db: database().await?,
-            links: Links::new(),
-        })
-    }
+async fn update_status(
+    cx: Context,
+    Path(id): Path<String>,
+    Json(params): Json<StatusParams>,
+) -> Html {
+    let link = cx.link(&id).await?;
+    let link = cx.set_status(link, params.status).await?;
+    let tags = cx.tags_for(std::slice::from_ref(&link)).await?;
+    let row = LinkRowComponent {
+        link: &link,
+        tags: tags.get(&link.id),
+    };
+
+    cx.partial(row)
 }
 
-fn text_input(name: &str) -> Markup {
-    html! {
-        input autofocus type="text" class="p-2 py-3 text-xl bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name=(name) tabindex="0";
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim();
+    match value.is_empty() {
+        true => None,
+        false => Some(value.to_owned()),
     }
 }
 
-fn button(name: &str) -> Markup {
-    html! {
-        button type="submit" class="px-2 py-4 bg-orange-500 rounded-md hover:bg-orange-400" {
-            (name)
-        }
-    }
+#[derive(Deserialize)]
+struct SearchParams {
+    #[serde(default)]
+    q: String,
 }
 
-fn now() -> f64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let now = SystemTime::now();
+async fn search(cx: Context, Query(params): Query<SearchParams>) -> Html {
+    let results = cx.search(&params.q).await?;
+    let component = SearchComponent {
+        q: params.q,
+        results,
+    };
 
-    now.duration_since(UNIX_EPOCH).unwrap().as_secs_f64()
+    cx.render(component)
 }
 
-impl From<rizz::Error> for Error {
-    fn from(value: rizz::Error) -> Self {
-        match value {
-            rizz::Error::ConnectionClosed => todo!(),
-            rizz::Error::Close(_) => todo!(),
-            rizz::Error::Database(err) => Error::Database(err),
-            rizz::Error::MissingFrom => todo!(),
-            rizz::Error::InsertError(_) => todo!(),
-            rizz::Error::SqlConversion(_) => todo!(),
-            rizz::Error::RowNotFound => Error::NotFound,
+struct QuickAddComponent {
+    params: QuickAddParams,
+    error: Option<String>,
+}
+
+impl Component for QuickAddComponent {
+    fn html(&self) -> Markup {
+        let QuickAddParams {
+            url,
+            title,
+            tags,
+            notes,
+            popup,
+        } = &self.params;
+        html! {
+            form class="flex flex-col w-full gap-3" action=(Route::QuickAdd) method="post" hx-boost="false" {
+                input type="hidden" name="popup" value=(popup);
+                input type="text" class="p-2 text-lg bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="url" value=(url) placeholder="url" required;
+                input type="text" class="p-2 text-lg bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="title" value=(title) placeholder="title";
+                input autofocus type="text" class="p-2 text-lg bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="tags" value=(tags) placeholder="tags, separated by commas";
+                textarea class="p-2 text-lg bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="notes" placeholder="notes" { (notes) }
+                @if let Some(err) = &self.error {
+                    p class="text-red-500" { (err) }
+                }
+                (button("Save"))
+            }
         }
     }
 }
 
-static DATABASE: OnceLock<Database> = OnceLock::new();
+struct SavedComponent;
 
-async fn database() -> Res<Database> {
-    let database = match DATABASE.get() {
-        Some(database) => database.clone(),
-        None => {
-            let connection = Connection::new("db.sqlite3")
-                .create_if_missing(true)
-                .journal_mode(rizz::JournalMode::Wal)
-                .synchronous(rizz::Synchronous::Normal)
-                .foreign_keys(true)
-                .open()
-                .await?;
-            let database = connection.database();
-            let _x = DATABASE
-                .set(database.clone())
-                .expect("failed to set DATABASE");
-            DATABASE.get().expect("failed to get DATABASE").clone()
+impl Component for SavedComponent {
+    fn html(&self) -> Markup {
+        html! {
+            p class="text-center" { "Saved!" }
+            script { "setTimeout(function () { window.close(); }, 600);" }
         }
-    };
-    Ok(database)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Deserialize, Default)]
+struct QuickAddParams {
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    notes: String,
+    /// Set by the popup bookmarklet, which wants the window closed instead
+    /// of being sent back to the page.
+    #[serde(default)]
+    popup: String,
+}
+
+async fn quick_add(cx: Context, Query(params): Query<QuickAddParams>) -> Html {
+    cx.render(QuickAddComponent {
+        params,
+        error: None,
+    })
+}
+
+async fn save_quick_add(cx: Context, Form(params): Form<QuickAddParams>) -> Res<Response> {
+    let url = params.url.trim().to_owned();
+    if let Err(err) = validate_url(&url) {
+        let component = QuickAddComponent {
+            params,
+            error: Some(err.message()),
+        };
+        return Ok(cx.render(component).into_response());
+    }
+    let saved = cx
+        .save_from_anywhere(SaveParams {
+            url,
+            title: non_empty(params.title),
+            notes: non_empty(params.notes),
+            tags: tag_names(&params.tags),
+        })
+        .await?;
+    match params.popup.is_empty() {
+        true => Ok(Redirect::to(&saved.url).into_response()),
+        false => Ok(cx.render(SavedComponent).into_response()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ShareParams {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    url: String,
+}
+
+impl ShareParams {
+    /// Plenty of apps leave `url` empty and put the link somewhere in
+    /// `text`, so look there too.
+    fn quick_add(self) -> QuickAddParams {
+        let from_text = self
+            .text
+            .split_whitespace()
+            .find(|word| word.starts_with("https://") || word.starts_with("http://"))
+            .map(|word| word.trim_end_matches(|c: char| ")]>.,;!?\"'".contains(c)));
+        let url = match self.url.trim() {
+            "" => from_text.unwrap_or_default().to_owned(),
+            url => url.to_owned(),
+        };
+        let notes = self.text.replace(&url, "");
+        QuickAddParams {
+            url,
+            title: self.title,
+            notes,
+            ..Default::default()
+        }
+    }
+}
+
+/// Web share target from the manifest, which posts what was shared here.
+async fn share(cx: Context, Form(params): Form<ShareParams>) -> Res<Response> {
+    let params = params.quick_add();
+    validate_url(&params.url)?;
+    let _link = cx
+        .save_from_anywhere(SaveParams {
+            url: params.url,
+            title: non_empty(params.title),
+            notes: non_empty(params.notes),
+            tags: vec![],
+        })
+        .await?;
+    Ok(Redirect::to(Route::Home.into()).into_response())
+}
+
+/// A share that arrives as a plain link gets the quick add form to confirm
+/// it instead, so following a link can never save anything.
+async fn share_form(cx: Context, Query(params): Query<ShareParams>) -> Html {
+    cx.render(QuickAddComponent {
+        params: params.quick_add(),
+        error: None,
+    })
+}
+
+/// What the bookmarklet and other "save from anywhere" entry points send.
+struct SaveParams {
+    url: String,
+    title: Option<String>,
+    notes: Option<String>,
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LinkParams {
+    url: String,
+    #[serde(default)]
+    tags: String,
+    /// Moves an already saved link to the top instead of reporting it.
+    bump: Option<String>,
+}
+
+async fn add_link(cx: Context, Json(mut params): Json<LinkParams>) -> Res<Response> {
+    params.url = params.url.trim().to_owned();
+    if let Err(err) = validate_url(&params.url) {
+        if cx.json {
+            return Err(err);
+        }
+        let page = cx.links(&PageParams::default()).await?;
+        let tags = cx.tags_for(&page.items).await?;
+        let home = HomeComponent {
+            route: Route::Home,
+            url: params.url,
+            url_error: Some(err.message()),
+            duplicate: None,
+            page,
+            tags,
+            broken: cx.broken_count().await?,
+        };
+        return Ok(cx.render(home).into_response());
+    }
+    if let Some(existing) = cx.link_by_url(&params.url).await? {
+        if params.bump.as_deref() == Some("true") {
+            cx.bump_link(&existing.id).await?;
+            cx.tag_link(&existing.id, &tag_names(&params.tags)).await?;
+            if cx.json {
+                let link = cx.link(&existing.id).await?;
+                return Ok(Json(cx.json_link(link).await?).into_response());
+            }
+            return Ok(Redirect::to(Route::Home.into()).into_response());
+        }
+        if cx.json {
+            return Err(Error::Conflict(format!(
+                "{} is already saved on {}",
+                existing.url,
+                date(existing.created_at)
+            )));
+        }
+        let page = cx.links(&PageParams::default()).await?;
+        let tags = cx.tags_for(&page.items).await?;
+        let home = HomeComponent {
+            route: Route::Home,
+            url: String::new(),
+            url_error: None,
+            duplicate: Some((existing, params.tags)),
+            page,
+            tags,
+            broken: cx.broken_count().await?,
+        };
+        return Ok(cx.render(home).into_response());
+    }
+    let link = cx
+        .create_link(Link::new(&cx.user.id, params.url), &tag_names(&params.tags))
+        .await?;
+    if cx.json {
+        let link = cx.json_link(link).await?;
+        return Ok((StatusCode::CREATED, Json(link)).into_response());
+    }
+    Ok(Redirect::to(Route::Home.into()).into_response())
+}
+
+fn validate_url(url: &str) -> Res<()> {
+    let Config {
+        allowed_schemes,
+        max_url_length,
+        ..
+    } = config();
+    normalize::validate(url, allowed_schemes, *max_url_length).map_err(Error::Validation)
+}
+
+#[derive(Default)]
+struct ImportSummary {
+    added: usize,
+    duplicates: usize,
+    invalid: usize,
+}
+
+struct ImportComponent {
+    summary: Option<ImportSummary>,
+}
+
+impl Component for ImportComponent {
+    fn html(&self) -> Markup {
+        html! {
+            h2 class="text-xl" { "Import bookmarks" }
+            p class="text-gray-500" {
+                "Upload the bookmarks.html file your browser exports. Folders become tags."
+            }
+            form class="flex flex-col w-full gap-3" action=(Route::Import) method="post" enctype="multipart/form-data" hx-boost="false" {
+                input type="file" name="file" accept=".html,.htm,text/html" required;
+                (button("Import"))
+            }
+            @if let Some(summary) = &self.summary {
+                p {
+                    "Added " (summary.added) ", skipped " (summary.duplicates) " already saved and "
+                    (summary.invalid) " invalid bookmarks."
+                }
+            }
+            a class="text-sky-500 underline hover:text-sky-300" href=(Route::Export) hx-boost="false" { "Export all links" }
+            a class="text-sky-500 underline hover:text-sky-300" href=(archive::ArchiveRoute::Export) hx-boost="false" { "Export archived pages as WARC" }
+        }
+    }
+}
+
+async fn import(cx: Context) -> Html {
+    cx.render(ImportComponent { summary: None })
+}
+
+async fn import_bookmarks(cx: Context, mut multipart: Multipart) -> Html {
+    let mut html = String::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| Error::BadRequest(err.to_string()))?
+    {
+        if field.name() == Some("file") {
+            html = field
+                .text()
+                .await
+                .map_err(|err| Error::BadRequest(err.to_string()))?;
+        }
+    }
+    let mut summary = ImportSummary::default();
+    for bookmark in bookmarks::parse(&html) {
+        if validate_url(&bookmark.url).is_err() {
+            summary.invalid += 1;
+            continue;
+        }
+        let names: Vec<String> = bookmark
+            .tags
+            .iter()
+            .map(|tag| tag.split_whitespace().collect::<Vec<_>>().join("-"))
+            .collect();
+        let names = tag_names(&names.join(","));
+        if let Some(existing) = cx.link_by_url(&bookmark.url).await? {
+            cx.tag_link(&existing.id, &names).await?;
+            summary.duplicates += 1;
+            continue;
+        }
+        let mut link = Link::new(&cx.user.id, bookmark.url);
+        link.created_at = bookmark.add_date.unwrap_or(link.created_at);
+        link.title = bookmark.title;
+        link.notes = bookmark.description;
+        let link_id = link.id.clone();
+        let _rows_affected = cx
+            .db
+            .insert_into(cx.links)
+            .values(link)?
+            .rows_affected()
+            .await?;
+        cx.tag_link(&link_id, &names).await?;
+        summary.added += 1;
+    }
+
+    cx.render(ImportComponent {
+        summary: Some(summary),
+    })
+}
+
+async fn export(cx: Context) -> Res<impl IntoResponse> {
+    let links = cx.all_links().await?;
+    let mut tags = cx.all_tags().await?;
+    let bookmarks: Vec<bookmarks::Bookmark> = links
+        .into_iter()
+        .map(|link| bookmarks::Bookmark {
+            tags: tags.remove(&link.id).unwrap_or_default(),
+            title: link.title.or(link.og_title),
+            description: link.notes,
+            add_date: Some(link.created_at),
+            url: link.url,
+        })
+        .collect();
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"bookmarks.html\"",
+            ),
+        ],
+        bookmarks::export(&bookmarks),
+    ))
+}
+
+fn tag_names(tags: &str) -> Vec<String> {
+    let mut names: Vec<String> = tags
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .map(|name| name.trim().trim_start_matches('#').to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+async fn not_found() -> impl IntoResponse {
+    Error::NotFound
+}
+
+async fn files(uri: axum::http::Uri) -> impl IntoResponse {
+    let mut path = uri.path().trim_start_matches('/').to_string();
+    if path.starts_with("pub/") {
+        path = path.replace("pub/", "");
+    }
+    StaticFile(path)
+}
+
+#[derive(rust_embed::RustEmbed)]
+#[folder = "pub"]
+pub struct Files;
+
+pub struct StaticFile<T>(pub T);
+
+impl<T> StaticFile<T>
+where
+    T: Into<String>,
+{
+    fn maybe_response(self) -> Res<axum::response::Response> {
+        let path = self.0.into();
+        let asset = Files::get(path.as_str()).ok_or(Error::NotFound)?;
+        let body = axum::body::boxed(axum::body::Full::from(asset.data));
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+        let response = axum::response::Response::builder()
+            .header(axum::http::header::CONTENT_TYPE, mime.as_ref())
+            .header(axum::http::header::CACHE_CONTROL, "public, max-age=604800")
+            .body(body)
+            .map_err(|_| Error::NotFound)?;
+        Ok(response)
+    }
+}
+
+impl<T> IntoResponse for StaticFile<T>
+where
+    T: Into<String>,
+{
+    fn into_response(self) -> axum::response::Response {
+        self.maybe_response()
+            .unwrap_or(Error::NotFound.into_response())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Error {
+    NotFound,
+    Database(String),
+    Fetch(String),
+    Validation(String),
+    Conflict(String),
+    Unauthorized,
+    Forbidden,
+    BadRequest(String),
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    status: u16,
+    code: &'static str,
+    message: String,
+}
+
+impl Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Database(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Fetch(_) => StatusCode::BAD_GATEWAY,
+            Error::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::NotFound => "not_found",
+            Error::Database(_) => "database",
+            Error::Fetch(_) => "fetch",
+            Error::Validation(_) => "validation",
+            Error::Conflict(_) => "conflict",
+            Error::Unauthorized => "unauthorized",
+            Error::Forbidden => "forbidden",
+            Error::BadRequest(_) => "bad_request",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    /// What the client gets to see; server side causes stay in the logs.
+    fn message(&self) -> String {
+        match self {
+            Error::NotFound => "not found".into(),
+            Error::Database(_) | Error::Internal(_) => "internal server error".into(),
+            Error::Fetch(_) => "could not fetch that page".into(),
+            Error::Validation(message) | Error::Conflict(message) | Error::BadRequest(message) => {
+                message.clone()
+            }
+            Error::Unauthorized => "unauthorized".into(),
+            Error::Forbidden => "that token isn't allowed to do this".into(),
+        }
+    }
+
+    fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: ErrorDetail {
+                status: self.status().as_u16(),
+                code: self.code(),
+                message: self.message(),
+            },
+        }
+    }
+}
+
+type Res<T> = Result<T, Error>;
+type Html = Res<Markup>;
+
+#[derive(Clone)]
+struct Context {
+    db: Database,
+    links: Links,
+    fetcher: Arc<dyn Fetch>,
+    htmx: bool,
+    json: bool,
+    user: User,
+    /// Set when the request was authenticated with an api token instead of
+    /// a session cookie.
+    token: Option<ApiToken>,
+}
+
+trait Component {
+    fn html(&self) -> Markup;
+}
+
+fn layout(component: impl Component, user: Option<&User>) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                title { "links" }
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                meta charset="UTF-8";
+                meta name="theme-color" content="#f97316";
+                link rel="manifest" href=(Route::Manifest);
+                link rel="icon" href="/pub/icon.svg" type="image/svg+xml";
+                link rel="stylesheet" href="/pub/tailwind.css";
+                script src="/pub/htmx.org@1.9.5.js" {}
+                script src="/pub/json-enc.js" {}
+                script {
+                    "if ('serviceWorker' in navigator) { navigator.serviceWorker.register('" (Route::ServiceWorker) "'); }"
+                }
+            }
+            body class="bg-white dark:bg-gray-950 dark:text-white" hx-boost="true" hx-ext="json-enc" {
+                div class="max-w-lg mx-auto w-full lg:px-0 px-3 h-screen flex flex-col gap-3" {
+                    h1 class="text-2xl lg:text-4xl text-center" { a href=(Route::Home) { "links" } }
+                    @if let Some(user) = user {
+                        nav class="flex justify-center gap-4 text-sm text-gray-500" {
+                            a class="hover:text-sky-500" href=(Route::Import) { "import / export" }
+                            a class="hover:text-sky-500" href=(settings::SettingsRoute::Settings) { "settings" }
+                            a class="hover:text-sky-500" href=(jobs::JobsRoute::Jobs) { "jobs" }
+                            span { (user.email) }
+                            form action=(auth::AuthRoute::Logout) method="post" {
+                                button type="submit" class="hover:text-sky-500" { "log out" }
+                            }
+                        }
+                    }
+                    (component.html())
+                }
+            }
+        }
+    }
+}
+
+impl Context {
+    /// For endpoints whose http method doesn't say whether they write, like
+    /// the pinboard api doing everything over GET.
+    fn require_scope(&self, scope: Scope) -> Res<()> {
+        match &self.token {
+            Some(token) if !token.allows(scope) => Err(Error::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    /// Renders just the component for htmx swaps and falls back to a full
+    /// page for regular or boosted requests.
+    fn partial(&self, component: impl Component) -> Html {
+        match self.htmx {
+            true => Ok(component.html()),
+            false => self.render(component),
+        }
+    }
+
+    fn render(&self, component: impl Component) -> Html {
+        Ok(layout(component, Some(&self.user)))
+    }
+
+    async fn links(&self, params: &PageParams) -> Res<Page<Link>> {
+        let limit = PAGE_SIZE + 1;
+        let mut sql = String::from("select * from links where user_id = ?");
+        let mut values: Vec<rizz::Value> = vec![self.user.id.clone().into()];
+        if let Some(status) = params.status {
+            sql.push_str(" and status = ?");
+            values.push(status.as_str().into());
+        }
+        match (params.before(), params.after()) {
+            (Some(cursor), _) => {
+                sql.push_str(
+                    " and (created_at < ? or (created_at = ? and id < ?))
+                     order by created_at desc, id desc",
+                );
+                values.extend([
+                    cursor.created_at.into(),
+                    cursor.created_at.into(),
+                    cursor.id.into(),
+                ]);
+            }
+            (None, Some(cursor)) => {
+                sql.push_str(
+                    " and (created_at > ? or (created_at = ? and id > ?))
+                     order by created_at asc, id asc",
+                );
+                values.extend([
+                    cursor.created_at.into(),
+                    cursor.created_at.into(),
+                    cursor.id.into(),
+                ]);
+            }
+            (None, None) => sql.push_str(" order by created_at desc, id desc"),
+        }
+        sql.push_str(" limit ?");
+        values.push((limit as i64).into());
+        let mut rows: Vec<Link> = self.db.query(&sql, values).all().await?;
+        let has_more = rows.len() > PAGE_SIZE;
+        rows.truncate(PAGE_SIZE);
+        let going_newer = params.before().is_none() && params.after().is_some();
+        if going_newer {
+            rows.reverse();
+        }
+        let first = rows.first().map(Cursor::from);
+        let last = rows.last().map(Cursor::from);
+        let (newer, older) = match going_newer {
+            true => (first.filter(|_| has_more), last),
+            false => (
+                first.filter(|_| params.before().is_some()),
+                last.filter(|_| has_more),
+            ),
+        };
+        Ok(Page {
+            items: rows,
+            newer: newer.map(|cursor| cursor.to_string()),
+            older: older.map(|cursor| cursor.to_string()),
+        })
+    }
+
+    async fn link(&self, id: &str) -> Res<Link> {
+        let Context { db, links, .. } = &self;
+        let link = db
+            .select()
+            .from(*links)
+            .where_(and(eq(links.id, id), eq(links.user_id, &self.user.id)))
+            .first()
+            .await?;
+        Ok(link)
+    }
+
+    /// Inserts a new link with its tags and, for web pages, queues metadata
+    /// capture, archiving and article extraction.
+    async fn create_link(&self, link: Link, tags: &[String]) -> Res<Link> {
+        let link_id = link.id.clone();
+        let _rows_affected = self
+            .db
+            .insert_into(self.links)
+            .values(link)?
+            .rows_affected()
+            .await?;
+        self.tag_link(&link_id, tags).await?;
+        let link = self.link(&link_id).await?;
+        if link.is_fetchable() {
+            for kind in [JobKind::Metadata, JobKind::Archive, JobKind::Read] {
+                self.enqueue(kind, &link_id).await?;
+            }
+        }
+        Ok(link)
+    }
+
+    async fn save_link(&self, link: &Link) -> Res<()> {
+        let _rows_affected = self
+            .db
+            .execute(
+                "update links set url = ?, normalized_url = ?, title = ?, notes = ? where id = ? and user_id = ?",
+                vec![
+                    link.url.clone().into(),
+                    link.normalized_url.clone().into(),
+                    link.title.clone().into(),
+                    link.notes.clone().into(),
+                    link.id.clone().into(),
+                    self.user.id.clone().into(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Moves a link between unread, read and archived. `read_at` records
+    /// when it was first finished and is cleared again when marked unread.
+    async fn set_status(&self, mut link: Link, status: Status) -> Res<Link> {
+        link.read_at = match status {
+            Status::Unread => None,
+            Status::Read | Status::Archived => link.read_at.or(Some(now())),
+        };
+        link.status = status;
+        let _rows_affected = self
+            .db
+            .execute(
+                "update links set status = ?, read_at = ? where id = ? and user_id = ?",
+                vec![
+                    status.as_str().into(),
+                    link.read_at.into(),
+                    link.id.clone().into(),
+                    self.user.id.clone().into(),
+                ],
+            )
+            .await?;
+        Ok(link)
+    }
+
+    async fn delete_link(&self, id: &str) -> Res<()> {
+        let Context { db, links, .. } = &self;
+        let _rows_affected = db
+            .delete_from(*links)
+            .where_(and(eq(links.id, id), eq(links.user_id, &self.user.id)))
+            .rows_affected()
+            .await?;
+        Ok(())
+    }
+
+    async fn set_tags(&self, link_id: &str, names: &[String]) -> Res<()> {
+        let _rows_affected = self
+            .db
+            .execute(
+                "delete from link_tags where link_id = ?",
+                vec![link_id.into()],
+            )
+            .await?;
+        self.tag_link(link_id, names).await
+    }
+
+    async fn json_link(&self, link: Link) -> Res<LinkJson> {
+        let mut tags = self.tags_for(std::slice::from_ref(&link)).await?;
+        Ok(LinkJson {
+            tags: tags.remove(&link.id).unwrap_or_default(),
+            link,
+        })
+    }
+
+    async fn json_page(&self, page: Page<Link>) -> Res<Page<LinkJson>> {
+        let mut tags = self.tags_for(&page.items).await?;
+        let items = page
+            .items
+            .into_iter()
+            .map(|link| LinkJson {
+                tags: tags.remove(&link.id).unwrap_or_default(),
+                link,
+            })
+            .collect();
+        Ok(Page {
+            items,
+            newer: page.newer,
+            older: page.older,
+        })
+    }
+
+    /// Saves a link sent from outside the app. Saving one that is already
+    /// there fills in the new title and notes, adds the tags and moves it
+    /// to the top instead of failing.
+    async fn save_from_anywhere(&self, params: SaveParams) -> Res<Link> {
+        let SaveParams {
+            url,
+            title,
+            notes,
+            tags,
+        } = params;
+        match self.link_by_url(&url).await? {
+            Some(mut link) => {
+                link.title = title.or(link.title);
+                link.notes = notes.or(link.notes);
+                self.save_link(&link).await?;
+                self.tag_link(&link.id, &tags).await?;
+                self.bump_link(&link.id).await?;
+                Ok(link)
+            }
+            None => {
+                let mut link = Link::new(&self.user.id, url);
+                link.title = title;
+                link.notes = notes;
+                self.create_link(link, &tags).await
+            }
+        }
+    }
+
+    async fn all_links(&self) -> Res<Vec<Link>> {
+        let Context { db, links, .. } = &self;
+        let rows = db
+            .select()
+            .from(*links)
+            .where_(eq(links.user_id, &self.user.id))
+            .order(vec![desc(links.created_at), desc(links.id)])
+            .all()
+            .await?;
+        Ok(rows)
+    }
+
+    async fn all_tags(&self) -> Res<HashMap<String, Vec<String>>> {
+        let rows: Vec<LinkTagName> = self
+            .db
+            .query(
+                "select link_tags.link_id, tags.name from link_tags
+                 join tags on tags.id = link_tags.tag_id
+                 join links on links.id = link_tags.link_id
+                 where links.user_id = ?
+                 order by tags.name",
+                vec![self.user.id.clone().into()],
+            )
+            .all()
+            .await?;
+        let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+        for row in rows {
+            tags.entry(row.link_id).or_default().push(row.name);
+        }
+        Ok(tags)
+    }
+
+    /// Finds the user's link for `url`, ignoring differences that
+    /// normalization irons out.
+    async fn link_by_url(&self, url: &str) -> Res<Option<Link>> {
+        let rows: Vec<Link> = self
+            .db
+            .query(
+                "select * from links where user_id = ? and (normalized_url = ? or url = ?) limit 1",
+                vec![
+                    self.user.id.clone().into(),
+                    normalize_url(url).into(),
+                    url.into(),
+                ],
+            )
+            .all()
+            .await?;
+        Ok(rows.into_iter().next())
+    }
+
+    async fn bump_link(&self, id: &str) -> Res<()> {
+        let _rows_affected = self
+            .db
+            .execute(
+                "update links set created_at = ? where id = ? and user_id = ?",
+                vec![now().into(), id.into(), self.user.id.clone().into()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn search(&self, q: &str) -> Res<Vec<SearchResult>> {
+        let Some(q) = fts_query(q) else {
+            return Ok(vec![]);
+        };
+        let sql = format!(
+            "select links.*, snippet(links_fts, -1, '{}', '{}', '…', 16) as snippet
+             from links_fts
+             join links on links.rowid = links_fts.rowid
+             where links_fts match ? and links.user_id = ?
+             order by rank
+             limit 50",
+            MARK_START, MARK_END
+        );
+        let rows = self
+            .db
+            .query(&sql, vec![q.into(), self.user.id.clone().into()])
+            .all()
+            .await?;
+        Ok(rows)
+    }
+
+    async fn tagged_links(&self, name: &str) -> Res<Vec<Link>> {
+        let rows = self
+            .db
+            .query(
+                "select links.* from links
+                 join link_tags on link_tags.link_id = links.id
+                 join tags on tags.id = link_tags.tag_id
+                 where tags.name = ? and links.user_id = ?
+                 order by links.created_at desc",
+                vec![name.into(), self.user.id.clone().into()],
+            )
+            .all()
+            .await?;
+        Ok(rows)
+    }
+
+    async fn tags_for(&self, links: &[Link]) -> Res<HashMap<String, Vec<String>>> {
+        if links.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let placeholders = vec!["?"; links.len()].join(",");
+        let sql = format!(
+            "select link_tags.link_id, tags.name from link_tags
+             join tags on tags.id = link_tags.tag_id
+             where link_tags.link_id in ({})
+             order by tags.name",
+            placeholders
+        );
+        let values = links.iter().map(|link| link.id.clone().into()).collect();
+        let rows: Vec<LinkTagName> = self.db.query(&sql, values).all().await?;
+        let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+        for row in rows {
+            tags.entry(row.link_id).or_default().push(row.name);
+        }
+        Ok(tags)
+    }
+
+    /// Fills in a link's details from its page. A title the user gave when
+    /// saving it is kept.
+    async fn capture_metadata(&self, link_id: &str, url: &str) -> Res<()> {
+        let meta = Metadata::fetch(self.fetcher.as_ref(), url).await?;
+        let _rows_affected = self
+            .db
+            .execute(
+                "update links set title = coalesce(title, ?), description = ?, og_title = ?, og_image = ?, canonical_url = ? where id = ?",
+                vec![
+                    meta.title.into(),
+                    meta.description.into(),
+                    meta.og_title.into(),
+                    meta.og_image.into(),
+                    meta.canonical_url.into(),
+                    link_id.into(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn tag_link(&self, link_id: &str, names: &[String]) -> Res<()> {
+        for name in names {
+            let _rows_affected = self
+                .db
+                .execute(
+                    "insert into tags (id, name, created_at) values (?, ?, ?) on conflict (name) do nothing",
+                    vec![nanoid::nanoid!().into(), name.clone().into(), now().into()],
+                )
+                .await?;
+            let _rows_affected = self
+                .db
+                .execute(
+                    "insert into link_tags (link_id, tag_id, created_at)
+                     select ?, tags.id, ? from tags where tags.name = ?
+                     on conflict (link_id, tag_id) do nothing",
+                    vec![link_id.into(), now().into(), name.clone().into()],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::response::Response {
+        let status = self.status();
+        if status.is_server_error() {
+            log::error!("{} {:?}", status, self);
+        }
+        let mut response = (status, self.message()).into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Context {
+    type Rejection = Error;
+
+    /// Resolves the signed in user from a bearer token or the session cookie,
+    /// rejecting with `Error::Unauthorized` when there is neither.
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let db = database().await?;
+        if let Some(api_token) = auth::api_token(parts) {
+            return Context::for_token(parts, db, &api_token).await;
+        }
+        let user = auth::session_user(&db, &parts.headers)
+            .await?
+            .ok_or(Error::Unauthorized)?;
+        Ok(Context::new(parts, db, user, None))
+    }
+}
+
+impl Context {
+    fn new(parts: &Parts, db: Database, user: User, token: Option<ApiToken>) -> Self {
+        Context {
+            db,
+            links: Links::new(),
+            fetcher: fetcher(),
+            htmx: is_htmx(&parts.headers),
+            json: accepts_json(&parts.headers),
+            user,
+            token,
+        }
+    }
+
+    /// Signs in as the owner of an api token, as long as the token's scopes
+    /// cover the request's method.
+    async fn for_token(parts: &Parts, db: Database, api_token: &str) -> Res<Self> {
+        let (user, token) = auth::token_user(&db, api_token)
+            .await?
+            .ok_or(Error::Unauthorized)?;
+        if !token.allows(Scope::for_method(&parts.method)) {
+            return Err(Error::Forbidden);
+        }
+        Ok(Context::new(parts, db, user, Some(token)))
+    }
+}
+
+fn text_input(name: &str, value: &str, error: Option<&str>) -> Markup {
+    let error_id = format!("{}-error", name);
+    html! {
+        div class="flex flex-col gap-1" {
+            input autofocus type="text" class="w-full p-2 py-3 text-xl bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name=(name) value=(value) tabindex="0" aria-invalid=[error.map(|_| "true")] aria-describedby=[error.map(|_| &error_id)];
+            @if let Some(error) = error {
+                p id=(error_id) class="text-sm text-red-500" { (error) }
+            }
+        }
+    }
+}
+
+fn text_field(name: &str, placeholder: &str) -> Markup {
+    html! {
+        input type="text" class="p-2 py-3 text-xl bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name=(name) placeholder=(placeholder);
+    }
+}
+
+fn tag_chip(name: &str) -> Markup {
+    html! {
+        a class="px-2 py-1 text-sm rounded-full bg-gray-100 dark:bg-gray-800 hover:bg-gray-200 dark:hover:bg-gray-700" href=(Route::Tag.path(name)) {
+            "#" (name)
+        }
+    }
+}
+
+const PAGE_SIZE: usize = 25;
+
+/// A page of rows ordered newest first, with opaque cursors pointing at the
+/// pages on either side of it.
+#[derive(Serialize)]
+struct Page<T> {
+    items: Vec<T>,
+    newer: Option<String>,
+    older: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct PageParams {
+    before: Option<String>,
+    after: Option<String>,
+    status: Option<Status>,
+}
+
+impl PageParams {
+    fn before(&self) -> Option<Cursor> {
+        self.before.as_deref().and_then(Cursor::parse)
+    }
+
+    fn after(&self) -> Option<Cursor> {
+        self.after.as_deref().and_then(Cursor::parse)
+    }
+}
+
+/// Position of a link in the (created_at, id) ordering, encoded as
+/// `created_at:id` in urls.
+struct Cursor {
+    created_at: f64,
+    id: String,
+}
+
+impl Cursor {
+    fn parse(value: &str) -> Option<Self> {
+        let (created_at, id) = value.split_once(':')?;
+        Some(Self {
+            created_at: created_at.parse().ok()?,
+            id: id.to_owned(),
+        })
+    }
+}
+
+impl From<&Link> for Cursor {
+    fn from(link: &Link) -> Self {
+        Self {
+            created_at: link.created_at,
+            id: link.id.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.created_at, self.id)
+    }
+}
+
+fn page_url(route: &Route, direction: &str, cursor: &str) -> String {
+    format!("{}?{}={}", route, direction, cursor)
+}
+
+fn search_form(q: &str) -> Markup {
+    html! {
+        form class="flex w-full gap-3" action=(Route::Search) method="get" {
+            input type="search" class="flex-1 p-2 text-lg bg-gray-100 dark:bg-gray-600 rounded-md outline-none" name="q" value=(q) placeholder="search links";
+        }
+    }
+}
+
+const MARK_START: char = '\u{2}';
+const MARK_END: char = '\u{3}';
+
+/// Renders an fts5 snippet, turning the match markers into <mark> tags
+/// while escaping everything else.
+fn highlight(snippet: &str) -> Markup {
+    html! {
+        @for (i, part) in snippet.split(MARK_START).enumerate() {
+            @if i == 0 {
+                (part)
+            } @else {
+                @let (marked, rest) = part.split_once(MARK_END).unwrap_or((part, ""));
+                mark class="bg-orange-200 dark:bg-orange-700 dark:text-white" { (marked) }
+                (rest)
+            }
+        }
+    }
+}
+
+/// Turns free text into an fts5 query where every term is quoted and
+/// prefix matched, so user input can't trip the fts5 query syntax.
+fn fts_query(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+    match terms.is_empty() {
+        true => None,
+        false => Some(terms.join(" ")),
+    }
+}
+
+fn button(name: &str) -> Markup {
+    html! {
+        button type="submit" class="px-2 py-4 bg-orange-500 rounded-md hover:bg-orange-400" {
+            (name)
+        }
+    }
+}
+
+fn date(timestamp: f64) -> String {
+    chrono::DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|datetime| datetime.format("%b %-d, %Y").to_string())
+        .unwrap_or_default()
+}
+
+fn now() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let now = SystemTime::now();
+
+    now.duration_since(UNIX_EPOCH).unwrap().as_secs_f64()
+}
+
+impl From<rizz::Error> for Error {
+    fn from(value: rizz::Error) -> Self {
+        match value {
+            rizz::Error::ConnectionClosed => Error::Internal("database connection closed".into()),
+            rizz::Error::Close(err) => Error::Internal(format!("closing database: {}", err)),
+            rizz::Error::Database(err) if err.contains("UNIQUE constraint failed") => {
+                Error::Conflict("that already exists".into())
+            }
+            rizz::Error::Database(err) => Error::Database(err),
+            rizz::Error::MissingFrom => Error::Internal("query is missing a from clause".into()),
+            rizz::Error::InsertError(err) if err.contains("UNIQUE constraint failed") => {
+                Error::Conflict("that already exists".into())
+            }
+            rizz::Error::InsertError(err) => Error::BadRequest(format!("could not save: {}", err)),
+            rizz::Error::SqlConversion(err) => {
+                Error::Internal(format!("converting sql value: {}", err))
+            }
+            rizz::Error::RowNotFound => Error::NotFound,
+        }
+    }
+}
+
+/// One request and its response, as seen by a fetcher.
+#[derive(Clone, Debug)]
+struct Fetched {
+    /// The url this request went to. After redirects, that's the last hop.
+    url: String,
+    /// What was sent, as far as we know it: our own headers plus the client's.
+    request_headers: Vec<(String, String)>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// The redirect responses followed on the way here, first one first.
+    redirects: Vec<Fetched>,
+}
+
+impl Fetched {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The mime type without parameters, e.g. `text/css`.
+    fn content_type(&self) -> Option<String> {
+        let value = self.header("content-type")?;
+        let mime = value.split(';').next()?.trim().to_lowercase();
+        match mime.is_empty() {
+            true => None,
+            false => Some(mime),
+        }
+    }
+
+    fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// Every exchange it took to get this response, redirects first.
+    fn chain(&self) -> Vec<Fetched> {
+        let mut chain = self.redirects.clone();
+        chain.push(Fetched {
+            redirects: vec![],
+            ..self.clone()
+        });
+        chain
+    }
+}
+
+/// Only http and https urls can be fetched. Links with the other allowed
+/// schemes, like `mailto:` or `gemini:`, are kept as they are.
+fn fetchable(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+#[async_trait]
+trait Fetch: Send + Sync {
+    async fn fetch(&self, url: &str) -> Res<Fetched>;
+
+    /// Fetchers without a cheaper way to check a url just fetch it.
+    async fn head(&self, url: &str) -> Res<Fetched> {
+        self.fetch(url).await
+    }
+}
+
+const USER_AGENT: &str = concat!("links/", env!("CARGO_PKG_VERSION"));
+
+const MAX_REDIRECTS: usize = 10;
+
+/// Responses are cut off at this size while they're read, so one huge file
+/// can't use up the server's memory.
+const MAX_BODY_BYTES: usize = 20 * 1024 * 1024;
+
+struct HttpFetch {
+    client: reqwest::Client,
+    /// Refuse hosts on this machine or its networks.
+    public_only: bool,
+}
+
+impl HttpFetch {
+    fn new(public_only: bool) -> Self {
+        // redirects are followed by hand so every hop is seen and recorded
+        let mut builder = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(std::time::Duration::from_secs(10))
+            .redirect(reqwest::redirect::Policy::none());
+        if public_only {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+        let client = builder.build().expect("failed to build http client");
+        Self {
+            client,
+            public_only,
+        }
+    }
+}
+
+/// Resolves hosts to their public addresses only, so a saved link can't
+/// point the server at itself, its network or a cloud metadata endpoint.
+/// Checking here rather than before the request means a host can't swap
+/// its address in between.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && (64..128).contains(&b);
+            !(a == 0
+                || a >= 224
+                || shared
+                || ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                let unique_local = first & 0xfe00 == 0xfc00;
+                let link_local = first & 0xffc0 == 0xfe80;
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    || unique_local
+                    || link_local)
+            }
+        },
+    }
+}
+
+#[async_trait]
+impl Fetch for HttpFetch {
+    async fn fetch(&self, url: &str) -> Res<Fetched> {
+        self.follow(reqwest::Method::GET, url).await
+    }
+
+    async fn head(&self, url: &str) -> Res<Fetched> {
+        self.follow(reqwest::Method::HEAD, url).await
+    }
+}
+
+impl HttpFetch {
+    async fn follow(&self, method: reqwest::Method, url: &str) -> Res<Fetched> {
+        let mut url = url::Url::parse(url).map_err(|err| Error::Fetch(err.to_string()))?;
+        let mut redirects = vec![];
+        loop {
+            // ip addresses in urls never reach the resolver
+            let ip = match url.host() {
+                Some(url::Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+                Some(url::Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+                _ => None,
+            };
+            if self.public_only && ip.is_some_and(|ip| !is_public(ip)) {
+                return Err(Error::Fetch(format!("{} is not a public address", url)));
+            }
+            let mut fetched = self
+                .send(self.client.request(method.clone(), url.clone()))
+                .await?;
+            let location = match (300..400).contains(&fetched.status) {
+                true => fetched
+                    .header("location")
+                    .and_then(|location| url.join(location).ok()),
+                false => None,
+            };
+            let Some(location) = location else {
+                fetched.redirects = redirects;
+                return Ok(fetched);
+            };
+            if redirects.len() == MAX_REDIRECTS {
+                return Err(Error::Fetch(format!("{} redirected too many times", url)));
+            }
+            redirects.push(fetched);
+            url = location;
+        }
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Res<Fetched> {
+        let request = request.build()?;
+        let url = request.url().to_string();
+        let sent = header_pairs(request.headers());
+        let mut response = self.client.execute(request).await?;
+        let status = response.status().as_u16();
+        let mut request_headers = vec![
+            ("user-agent".to_owned(), USER_AGENT.to_owned()),
+            ("accept-encoding".to_owned(), "gzip".to_owned()),
+        ];
+        request_headers.extend(sent);
+        let headers = header_pairs(response.headers());
+        let too_big = || Error::Fetch(format!("{} is over {} bytes", url, MAX_BODY_BYTES));
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_BODY_BYTES as u64)
+        {
+            return Err(too_big());
+        }
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > MAX_BODY_BYTES {
+                return Err(too_big());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Fetched {
+            url,
+            request_headers,
+            status,
+            headers,
+            body,
+            redirects: vec![],
+        })
+    }
+}
+
+fn header_pairs(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+static FETCHER: OnceLock<Arc<dyn Fetch>> = OnceLock::new();
+
+fn fetcher() -> Arc<dyn Fetch> {
+    FETCHER
+        .get_or_init(|| Arc::new(HttpFetch::new(config().block_private_addresses)))
+        .clone()
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Internal(value.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        Error::Fetch(value.to_string())
+    }
+}
+
+#[derive(Default, Debug)]
+struct Metadata {
+    title: Option<String>,
+    description: Option<String>,
+    og_title: Option<String>,
+    og_image: Option<String>,
+    canonical_url: Option<String>,
+}
+
+impl Metadata {
+    /// Fetches `url` and reads its metadata, resolving relative urls
+    /// against wherever it redirected to.
+    async fn fetch(fetcher: &dyn Fetch, url: &str) -> Res<Self> {
+        let page = fetcher.fetch(url).await?;
+        match page.is_success() {
+            true => Ok(Self::parse(&page.text(), &page.url)),
+            false => Err(Error::Fetch(format!("{} returned {}", url, page.status))),
+        }
+    }
+
+    fn parse(html: &str, base: &str) -> Self {
+        use scraper::{Html, Selector};
+        let document = Html::parse_document(html);
+        let base = url::Url::parse(base).ok();
+        let select = |selector: &str, attr: Option<&str>| -> Option<String> {
+            let selector = Selector::parse(selector).ok()?;
+            let element = document.select(&selector).next()?;
+            let value = match attr {
+                Some(attr) => element.value().attr(attr)?.to_owned(),
+                None => element.text().collect::<String>(),
+            };
+            let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+            match value.is_empty() {
+                true => None,
+                false => Some(value),
+            }
+        };
+        let absolute = |value: Option<String>| -> Option<String> {
+            let value = value?;
+            match &base {
+                Some(base) => base.join(&value).ok().map(|url| url.to_string()),
+                None => Some(value),
+            }
+        };
+
+        Self {
+            title: select("title", None),
+            description: select("meta[name=description]", Some("content"))
+                .or_else(|| select("meta[property='og:description']", Some("content"))),
+            og_title: select("meta[property='og:title']", Some("content")),
+            og_image: absolute(select("meta[property='og:image']", Some("content"))),
+            canonical_url: absolute(select("link[rel=canonical]", Some("href"))),
+        }
+    }
+}
+
+static DATABASE: OnceLock<Database> = OnceLock::new();
+
+async fn database() -> Res<Database> {
+    let database = match DATABASE.get() {
+        Some(database) => database.clone(),
+        None => {
+            let Config {
+                database, sqlite, ..
+            } = config();
+            let connection = Connection::new(&database.to_string_lossy())
+                .create_if_missing(true)
+                .journal_mode(sqlite.journal_mode()?)
+                .synchronous(sqlite.synchronous()?)
+                .foreign_keys(sqlite.foreign_keys)
+                .open()
+                .await?;
+            let database = connection.database();
+            let _x = DATABASE
+                .set(database.clone())
+                .expect("failed to set DATABASE");
+            DATABASE.get().expect("failed to get DATABASE").clone()
+        }
+    };
+    Ok(database)
+}
+
+#[derive(Serialize, Deserialize)]
 struct Link {
     id: String,
+    user_id: String,
     url: String,
+    normalized_url: Option<String>,
     created_at: f64,
+    title: Option<String>,
+    description: Option<String>,
+    og_title: Option<String>,
+    og_image: Option<String>,
+    canonical_url: Option<String>,
+    notes: Option<String>,
+    status: Status,
+    read_at: Option<f64>,
+    captured_at: Option<f64>,
+    check_status: Option<i64>,
+    check_url: Option<String>,
+    checked_at: Option<f64>,
+    check_failures: i64,
+    /// Set once a dead link has been swapped for its archived copy.
+    replaced_at: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    #[default]
+    Unread,
+    Read,
+    Archived,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Unread => "unread",
+            Status::Read => "read",
+            Status::Archived => "archived",
+        }
+    }
+
+    /// The statuses a link can be moved to from this one, with button labels.
+    fn actions(&self) -> Vec<(Status, &'static str)> {
+        match self {
+            Status::Unread => vec![(Status::Read, "mark read"), (Status::Archived, "archive")],
+            Status::Read => vec![
+                (Status::Unread, "mark unread"),
+                (Status::Archived, "archive"),
+            ],
+            Status::Archived => vec![(Status::Unread, "unarchive")],
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LinkJson {
+    #[serde(flatten)]
+    link: Link,
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchResult {
+    #[serde(flatten)]
+    link: Link,
+    snippet: String,
+}
+
+impl Link {
+    fn new(user_id: &str, url: String) -> Self {
+        Self {
+            id: nanoid::nanoid!(),
+            user_id: user_id.to_owned(),
+            normalized_url: Some(normalize_url(&url)),
+            url,
+            created_at: now(),
+            title: None,
+            description: None,
+            og_title: None,
+            og_image: None,
+            canonical_url: None,
+            notes: None,
+            status: Status::Unread,
+            read_at: None,
+            captured_at: None,
+            check_status: None,
+            check_url: None,
+            checked_at: None,
+            check_failures: 0,
+            replaced_at: None,
+        }
+    }
+
+    fn set_url(&mut self, url: &str) {
+        self.url = url.to_owned();
+        self.normalized_url = Some(normalize_url(url));
+    }
+
+    fn dom_id(&self) -> String {
+        format!("link-{}", self.id)
+    }
+
+    fn display_title(&self) -> &str {
+        self.title
+            .as_deref()
+            .or(self.og_title.as_deref())
+            .unwrap_or(&self.url)
+    }
+
+    /// Where the link takes you: the page itself, or its archived copy
+    /// once it's been replaced by one.
+    fn href(&self) -> String {
+        match self.replaced_at {
+            Some(_) => archive::snapshot_url(&self.id),
+            None => self.url.clone(),
+        }
+    }
+
+    fn is_broken(&self) -> bool {
+        self.replaced_at.is_none() && self.check_failures >= checker::BROKEN_AFTER
+    }
+
+    fn is_fetchable(&self) -> bool {
+        fetchable(&self.url)
+    }
 }
 
 #[allow(unused)]
@@ -312,17 +2345,387 @@ struct Link {
 struct Links {
     #[rizz(primary_key)]
     id: Text,
+    user_id: Text,
     #[rizz(not_null)]
     url: Text,
+    normalized_url: Text,
     #[rizz(not_null)]
     created_at: Real,
+    title: Text,
+    description: Text,
+    og_title: Text,
+    og_image: Text,
+    canonical_url: Text,
+    notes: Text,
+    #[rizz(not_null)]
+    status: Text,
+    read_at: Real,
+    captured_at: Real,
+    check_status: Integer,
+    check_url: Text,
+    checked_at: Real,
+    #[rizz(not_null)]
+    check_failures: Integer,
+    replaced_at: Real,
 }
 
-async fn migrate(db: &Database) -> Res<()> {
-    let links = Links::new();
-    db.create_table(links)
-        .create_unique_index(links, vec![links.url])
-        .migrate()
-        .await?;
-    Ok(())
+#[allow(unused)]
+#[derive(Table, Clone, Copy)]
+#[rizz(table = "tags")]
+struct Tags {
+    #[rizz(primary_key)]
+    id: Text,
+    #[rizz(not_null)]
+    name: Text,
+    #[rizz(not_null)]
+    created_at: Real,
+}
+
+#[allow(unused)]
+#[derive(Table, Clone, Copy)]
+#[rizz(table = "link_tags")]
+struct LinkTags {
+    #[rizz(not_null, references = "links(id) on delete cascade")]
+    link_id: Text,
+    #[rizz(not_null, references = "tags(id) on delete cascade")]
+    tag_id: Text,
+    #[rizz(not_null)]
+    created_at: Real,
+}
+
+#[derive(Deserialize)]
+struct LinkTagName {
+    link_id: String,
+    name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers with canned responses by url, and a 404 for anything else.
+    #[derive(Default)]
+    pub struct Stub {
+        responses: HashMap<String, Fetched>,
+        /// Urls that answer HEAD with a 405, like servers that only do GET.
+        get_only: Vec<String>,
+    }
+
+    impl Stub {
+        pub fn page(self, url: &str, content_type: &str, body: &[u8]) -> Self {
+            self.response(
+                url,
+                200,
+                vec![("content-type".into(), content_type.into())],
+                body,
+            )
+        }
+
+        pub fn redirect(self, from: &str, to: &str) -> Self {
+            self.response(from, 301, vec![("location".into(), to.into())], b"")
+        }
+
+        pub fn get_only(mut self, url: &str) -> Self {
+            self.get_only.push(url.to_owned());
+            self
+        }
+
+        fn response(
+            mut self,
+            url: &str,
+            status: u16,
+            headers: Vec<(String, String)>,
+            body: &[u8],
+        ) -> Self {
+            let fetched = Fetched {
+                url: url.to_owned(),
+                request_headers: vec![],
+                status,
+                headers,
+                body: body.to_vec(),
+                redirects: vec![],
+            };
+            let _previous = self.responses.insert(url.to_owned(), fetched);
+            self
+        }
+    }
+
+    #[async_trait]
+    impl Fetch for Stub {
+        async fn fetch(&self, url: &str) -> Res<Fetched> {
+            let mut redirects = vec![];
+            let mut url = url.to_owned();
+            loop {
+                let Some(fetched) = self.responses.get(&url) else {
+                    return Ok(Fetched {
+                        url,
+                        request_headers: vec![],
+                        status: 404,
+                        headers: vec![],
+                        body: vec![],
+                        redirects,
+                    });
+                };
+                match fetched.header("location") {
+                    Some(location) if redirects.len() < MAX_REDIRECTS => {
+                        url = location.to_owned();
+                        redirects.push(fetched.clone());
+                    }
+                    _ => {
+                        return Ok(Fetched {
+                            redirects,
+                            ..fetched.clone()
+                        })
+                    }
+                }
+            }
+        }
+
+        async fn head(&self, url: &str) -> Res<Fetched> {
+            match self.get_only.iter().any(|get_only| get_only == url) {
+                true => Ok(Fetched {
+                    url: url.to_owned(),
+                    request_headers: vec![],
+                    status: 405,
+                    headers: vec![],
+                    body: vec![],
+                    redirects: vec![],
+                }),
+                false => self.fetch(url).await,
+            }
+        }
+    }
+
+    /// A fully migrated database that only this test sees.
+    pub async fn memory_db() -> Database {
+        let db = Connection::new(":memory:")
+            .create_if_missing(true)
+            .open()
+            .await
+            .unwrap()
+            .database();
+        migrate(&db).await.unwrap();
+        db
+    }
+
+    /// Signs up a new user and returns their context, fetching through
+    /// `fetcher`.
+    pub async fn signed_in(db: &Database, fetcher: impl Fetch + 'static) -> Context {
+        let id = nanoid::nanoid!();
+        let _rows_affected = db
+            .execute(
+                "insert into users (id, email, password_hash, created_at) values (?, ?, 'hash', ?)",
+                vec![
+                    id.clone().into(),
+                    format!("{}@example.com", id).into(),
+                    now().into(),
+                ],
+            )
+            .await
+            .unwrap();
+        let users: Vec<User> = db
+            .query("select * from users where id = ?", vec![id.into()])
+            .all()
+            .await
+            .unwrap();
+        Context {
+            db: db.clone(),
+            links: Links::new(),
+            fetcher: Arc::new(fetcher),
+            htmx: false,
+            json: false,
+            user: users.into_iter().next().unwrap(),
+            token: None,
+        }
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let meta = Metadata::parse(
+            r#"<html><head>
+            <title>
+              A   page
+            </title>
+            <meta property="og:description" content="From og">
+            <meta property="og:title" content="A page, for sharing">
+            <meta property="og:image" content="/images/card.png">
+            <link rel="canonical" href="../canonical">
+            </head></html>"#,
+            "https://example.com/posts/1",
+        );
+        assert_eq!(meta.title.as_deref(), Some("A page"));
+        assert_eq!(meta.description.as_deref(), Some("From og"));
+        assert_eq!(meta.og_title.as_deref(), Some("A page, for sharing"));
+        assert_eq!(
+            meta.og_image.as_deref(),
+            Some("https://example.com/images/card.png")
+        );
+        assert_eq!(
+            meta.canonical_url.as_deref(),
+            Some("https://example.com/canonical")
+        );
+
+        let meta = Metadata::parse(
+            r#"<meta name="description" content="From meta"><meta property="og:description" content="From og"><title> </title>"#,
+            "not a url",
+        );
+        assert_eq!(meta.title, None);
+        assert_eq!(meta.description.as_deref(), Some("From meta"));
+    }
+
+    #[test]
+    fn quotes_fts_terms() {
+        assert_eq!(fts_query("  "), None);
+        assert_eq!(
+            fts_query("rust  async"),
+            Some(r#""rust"* "async"*"#.to_owned())
+        );
+        assert_eq!(
+            fts_query(r#"say "hi" OR NEAR(a b)"#),
+            Some(r#""say"* """hi"""* "OR"* "NEAR(a"* "b)"*"#.to_owned())
+        );
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor::parse("1700000000.5:abc:def").unwrap();
+        assert_eq!(cursor.created_at, 1700000000.5);
+        assert_eq!(cursor.id, "abc:def");
+        assert_eq!(cursor.to_string(), "1700000000.5:abc:def");
+        assert!(Cursor::parse("abc").is_none());
+        assert!(Cursor::parse("yesterday:abc").is_none());
+    }
+
+    /// Serves `router` on a local port for the rest of the test.
+    fn serve(router: Router) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service());
+        let _server = tokio::spawn(server);
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn records_every_redirect() {
+        let base = serve(
+            Router::new()
+                .route("/a", get(|| async { Redirect::permanent("/b") }))
+                .route("/b", get(|| async { Redirect::to("/c?d=e") }))
+                .route("/c", get(|| async { "done" })),
+        );
+        let page = HttpFetch::new(false)
+            .fetch(&format!("{}/a", base))
+            .await
+            .unwrap();
+        assert_eq!(page.status, 200);
+        assert_eq!(page.text(), "done");
+        let hops: Vec<(String, u16)> = page
+            .chain()
+            .into_iter()
+            .map(|hop| (hop.url, hop.status))
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                (format!("{}/a", base), 308),
+                (format!("{}/b", base), 303),
+                (format!("{}/c?d=e", base), 200),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn cuts_off_huge_bodies() {
+        let base = serve(Router::new().route(
+            "/huge",
+            get(|| async {
+                // no content-length, so the cap has to kick in while reading
+                let (mut sender, body) = axum::body::Body::channel();
+                let _sending = tokio::spawn(async move {
+                    let chunk = vec![b'a'; 1024 * 1024];
+                    for _ in 0..=MAX_BODY_BYTES / chunk.len() {
+                        if sender.send_data(chunk.clone().into()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                Response::new(body)
+            }),
+        ));
+        let result = HttpFetch::new(false).fetch(&format!("{}/huge", base)).await;
+        assert!(matches!(result, Err(Error::Fetch(message)) if message.contains("bytes")));
+    }
+
+    #[test]
+    fn knows_public_addresses() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "224.0.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses() {
+        let base = serve(Router::new().route("/", get(|| async { "private" })));
+        let port = base.rsplit(':').next().unwrap();
+        let fetch = HttpFetch::new(true);
+        assert!(fetch.fetch(&base).await.is_err());
+        assert!(fetch
+            .fetch(&format!("http://localhost:{}/", port))
+            .await
+            .is_err());
+        assert!(fetch
+            .fetch(&format!("http://[::ffff:127.0.0.1]:{}/", port))
+            .await
+            .is_err());
+        assert!(HttpFetch::new(false).fetch(&base).await.is_ok());
+    }
+
+    #[test]
+    fn encodes_path_segments() {
+        assert_eq!(Route::Tag.path("read/watch"), "/tags/read%2Fwatch");
+        assert_eq!(path_segment("c++ & co"), "c%2B%2B%20%26%20co");
+        assert_eq!(path_segment("café"), "caf%C3%A9");
+        assert_eq!(path_segment("a-b_c.d~e"), "a-b_c.d~e");
+    }
+
+    #[tokio::test]
+    async fn fetches_metadata_after_redirects() {
+        let stub = Stub::default()
+            .redirect("http://example.com/a", "https://www.example.com/b/")
+            .page(
+                "https://www.example.com/b/",
+                "text/html",
+                br#"<title>B</title><meta property="og:image" content="card.png">"#,
+            );
+        let meta = Metadata::fetch(&stub, "http://example.com/a")
+            .await
+            .unwrap();
+        assert_eq!(meta.title.as_deref(), Some("B"));
+        assert_eq!(
+            meta.og_image.as_deref(),
+            Some("https://www.example.com/b/card.png")
+        );
+        assert!(Metadata::fetch(&stub, "http://example.com/missing")
+            .await
+            .is_err());
+    }
 }
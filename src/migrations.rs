@@ -0,0 +1,397 @@
+use rizz::Database;
+use serde::Deserialize;
+
+use crate::{now, Error, Res};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    up: &'static [&'static str],
+    down: &'static [&'static str],
+}
+
+/// Every schema change, oldest first. Append new migrations to the end and
+/// never edit one that has already shipped.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create links",
+        up: &[
+            "create table if not exists links (id text primary key, url text not null, created_at real not null)",
+            "create unique index if not exists links_url_unique_index on links (url)",
+        ],
+        down: &["drop table links"],
+    },
+    Migration {
+        version: 2,
+        name: "create tags",
+        up: &[
+            "create table if not exists tags (id text primary key, name text not null, created_at real not null)",
+            "create unique index if not exists tags_name_unique_index on tags (name)",
+            "create table if not exists link_tags (
+                link_id text not null references links(id) on delete cascade,
+                tag_id text not null references tags(id) on delete cascade,
+                created_at real not null
+            )",
+            "create unique index if not exists link_tags_link_id_tag_id_unique_index on link_tags (link_id, tag_id)",
+        ],
+        down: &["drop table link_tags", "drop table tags"],
+    },
+    Migration {
+        version: 3,
+        name: "add page metadata to links",
+        up: &[
+            "alter table links add column title text",
+            "alter table links add column description text",
+            "alter table links add column og_title text",
+            "alter table links add column og_image text",
+            "alter table links add column canonical_url text",
+            "alter table links add column notes text",
+        ],
+        down: &[
+            "alter table links drop column notes",
+            "alter table links drop column canonical_url",
+            "alter table links drop column og_image",
+            "alter table links drop column og_title",
+            "alter table links drop column description",
+            "alter table links drop column title",
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "create links full text index",
+        up: &[
+            "create virtual table links_fts using fts5(url, title, description, notes, content = 'links', content_rowid = 'rowid')",
+            "create trigger links_fts_insert after insert on links begin
+                insert into links_fts (rowid, url, title, description, notes)
+                values (new.rowid, new.url, new.title, new.description, new.notes);
+            end",
+            "create trigger links_fts_delete after delete on links begin
+                insert into links_fts (links_fts, rowid, url, title, description, notes)
+                values ('delete', old.rowid, old.url, old.title, old.description, old.notes);
+            end",
+            "create trigger links_fts_update after update on links begin
+                insert into links_fts (links_fts, rowid, url, title, description, notes)
+                values ('delete', old.rowid, old.url, old.title, old.description, old.notes);
+                insert into links_fts (rowid, url, title, description, notes)
+                values (new.rowid, new.url, new.title, new.description, new.notes);
+            end",
+            "insert into links_fts (links_fts) values ('rebuild')",
+        ],
+        down: &[
+            "drop trigger links_fts_update",
+            "drop trigger links_fts_delete",
+            "drop trigger links_fts_insert",
+            "drop table links_fts",
+        ],
+    },
+    Migration {
+        version: 5,
+        name: "create users and sessions",
+        up: &[
+            "create table users (
+                id text primary key,
+                email text not null,
+                password_hash text not null,
+                created_at real not null
+            )",
+            "create unique index users_email_unique_index on users (email)",
+            "create table sessions (
+                id text primary key,
+                user_id text not null references users(id) on delete cascade,
+                created_at real not null,
+                expires_at real not null
+            )",
+            "create index sessions_user_id_index on sessions (user_id)",
+        ],
+        down: &["drop table sessions", "drop table users"],
+    },
+    Migration {
+        version: 6,
+        name: "give links an owner",
+        up: &[
+            "alter table links add column user_id text",
+            "create index links_user_id_created_at_index on links (user_id, created_at)",
+            // links saved before accounts existed go to a placeholder admin
+            // account claimed with `links --create-admin`, never to whoever
+            // happened to sign up first
+            "insert into users (id, email, password_hash, created_at)
+             select 'admin', 'admin', '', cast(strftime('%s', 'now') as real)
+             where exists (select 1 from links)",
+            "update links set user_id = 'admin' where user_id is null",
+            "drop index links_url_unique_index",
+            "create unique index links_user_id_url_unique_index on links (user_id, url)",
+        ],
+        down: &[
+            "drop index links_user_id_url_unique_index",
+            "create unique index links_url_unique_index on links (url)",
+            "drop index links_user_id_created_at_index",
+            "alter table links drop column user_id",
+            "delete from users where password_hash = ''",
+        ],
+    },
+    Migration {
+        version: 7,
+        name: "create api tokens",
+        up: &[
+            "create table api_tokens (
+                id text primary key,
+                user_id text not null references users(id) on delete cascade,
+                name text not null,
+                token_hash text not null,
+                scopes text not null,
+                created_at real not null,
+                last_used_at real,
+                revoked_at real
+            )",
+            "create unique index api_tokens_token_hash_unique_index on api_tokens (token_hash)",
+            "create index api_tokens_user_id_index on api_tokens (user_id)",
+        ],
+        down: &["drop table api_tokens"],
+    },
+    Migration {
+        version: 8,
+        name: "add normalized urls",
+        up: &[
+            // filled in from rust by normalize_existing at startup
+            "alter table links add column normalized_url text",
+            "drop index links_user_id_url_unique_index",
+            "create unique index links_user_id_normalized_url_unique_index on links (user_id, normalized_url)",
+        ],
+        down: &[
+            "drop index links_user_id_normalized_url_unique_index",
+            "create unique index links_user_id_url_unique_index on links (user_id, url)",
+            "alter table links drop column normalized_url",
+        ],
+    },
+    Migration {
+        version: 9,
+        name: "add read status to links",
+        up: &[
+            "alter table links add column status text not null default 'unread'",
+            "alter table links add column read_at real",
+            "create index links_user_id_status_created_at_index on links (user_id, status, created_at)",
+        ],
+        down: &[
+            "drop index links_user_id_status_created_at_index",
+            "alter table links drop column read_at",
+            "alter table links drop column status",
+        ],
+    },
+    Migration {
+        version: 10,
+        name: "create snapshots",
+        up: &[
+            "create table snapshots (
+                link_id text primary key not null references links(id) on delete cascade,
+                url text not null,
+                html blob not null,
+                size integer not null,
+                captured_at real not null
+            )",
+            "alter table links add column captured_at real",
+        ],
+        down: &[
+            "alter table links drop column captured_at",
+            "drop table snapshots",
+        ],
+    },
+    Migration {
+        version: 11,
+        name: "create exchanges",
+        up: &[
+            "create table exchanges (
+                link_id text not null references links(id) on delete cascade,
+                position integer not null,
+                url text not null,
+                status integer not null,
+                request_headers text not null,
+                response_headers text not null,
+                body blob not null,
+                primary key (link_id, position)
+            )",
+        ],
+        down: &["drop table exchanges"],
+    },
+    Migration {
+        version: 12,
+        name: "create articles",
+        up: &[
+            "create table articles (
+                link_id text primary key not null references links(id) on delete cascade,
+                content text not null,
+                byline text,
+                published_at real,
+                word_count integer not null,
+                reading_time integer not null,
+                extracted_at real not null
+            )",
+        ],
+        down: &["drop table articles"],
+    },
+    Migration {
+        version: 13,
+        name: "create jobs",
+        up: &[
+            "create table jobs (
+                id text primary key,
+                user_id text not null references users(id) on delete cascade,
+                kind text not null,
+                link_id text not null references links(id) on delete cascade,
+                status text not null,
+                attempts integer not null default 0,
+                run_at real not null,
+                locked_until real,
+                last_error text,
+                created_at real not null,
+                updated_at real not null
+            )",
+            "create index jobs_status_run_at_index on jobs (status, run_at)",
+            "create index jobs_user_id_index on jobs (user_id)",
+        ],
+        down: &["drop table jobs"],
+    },
+    Migration {
+        version: 14,
+        name: "add link checks to links",
+        up: &[
+            "alter table links add column check_status integer",
+            "alter table links add column check_url text",
+            "alter table links add column checked_at real",
+            "alter table links add column check_failures integer not null default 0",
+            "alter table links add column replaced_at real",
+            "create index links_checked_at_index on links (checked_at)",
+        ],
+        down: &[
+            "drop index links_checked_at_index",
+            "alter table links drop column replaced_at",
+            "alter table links drop column check_failures",
+            "alter table links drop column checked_at",
+            "alter table links drop column check_url",
+            "alter table links drop column check_status",
+        ],
+    },
+];
+
+#[derive(Deserialize)]
+struct AppliedMigration {
+    version: i64,
+}
+
+async fn applied_versions(db: &Database) -> Res<Vec<i64>> {
+    let _rows_affected = db
+        .execute(
+            "create table if not exists schema_migrations (version integer primary key, name text not null, applied_at real not null)",
+            vec![],
+        )
+        .await?;
+    let rows: Vec<AppliedMigration> = db
+        .query(
+            "select version from schema_migrations order by version",
+            vec![],
+        )
+        .all()
+        .await?;
+    Ok(rows.into_iter().map(|row| row.version).collect())
+}
+
+pub async fn pending_migrations(db: &Database) -> Res<Vec<&'static Migration>> {
+    let applied = applied_versions(db).await?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+pub async fn migrate(db: &Database) -> Res<()> {
+    for migration in pending_migrations(db).await? {
+        log::info!("Migrating {:04} {}", migration.version, migration.name);
+        transaction(db, migration.up, async {
+            let _rows_affected = db
+                .execute(
+                    "insert into schema_migrations (version, name, applied_at) values (?, ?, ?)",
+                    vec![
+                        migration.version.into(),
+                        migration.name.into(),
+                        now().into(),
+                    ],
+                )
+                .await?;
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// Undoes every applied migration newer than `version`, newest first.
+pub async fn rollback(db: &Database, version: i64) -> Res<()> {
+    let applied = applied_versions(db).await?;
+    let migrations = MIGRATIONS
+        .iter()
+        .rev()
+        .filter(|migration| migration.version > version && applied.contains(&migration.version));
+    for migration in migrations {
+        log::info!("Rolling back {:04} {}", migration.version, migration.name);
+        transaction(db, migration.down, async {
+            let _rows_affected = db
+                .execute(
+                    "delete from schema_migrations where version = ?",
+                    vec![migration.version.into()],
+                )
+                .await?;
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+async fn transaction(
+    db: &Database,
+    statements: &[&str],
+    record: impl std::future::Future<Output = Res<()>>,
+) -> Res<()> {
+    let _rows_affected = db.execute("begin", vec![]).await?;
+    let result = async {
+        for sql in statements {
+            let _rows_affected = db.execute(sql, vec![]).await?;
+        }
+        record.await
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            let _rows_affected = db.execute("commit", vec![]).await?;
+            Ok(())
+        }
+        Err(err) => {
+            let _rows_affected = db.execute("rollback", vec![]).await?;
+            Err(Error::Internal(format!("migration failed: {:?}", err)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth, tests::memory_db, Link};
+
+    #[tokio::test]
+    async fn links_from_before_accounts_go_to_the_placeholder_admin() {
+        let db = memory_db().await;
+        rollback(&db, 5).await.unwrap();
+        for sql in [
+            "insert into users (id, email, password_hash, created_at) values ('first', 'first@example.com', 'hash', 1)",
+            "insert into links (id, url, created_at) values ('link', 'https://example.com', 2)",
+        ] {
+            let _rows_affected = db.execute(sql, vec![]).await.unwrap();
+        }
+        migrate(&db).await.unwrap();
+
+        let links: Vec<Link> = db.query("select * from links", vec![]).all().await.unwrap();
+        assert_eq!(links[0].user_id, "admin");
+        let admin = auth::unclaimed_admin(&db).await.unwrap().unwrap();
+        assert_eq!(admin.id, "admin");
+    }
+}
@@ -0,0 +1,659 @@
+//! Pinboard v1 api, so existing pinboard and delicious clients can be
+//! pointed at this instance. Clients authenticate with
+//! `auth_token=<anything>:<api token>` and get xml unless they ask for
+//! `format=json`. Everything is a GET, so writes check `links:write` by hand.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header, request::Parts},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    auth, database, non_empty, normalize_url, now, tag_names, validate_url, Context, Error, Link,
+    Res, Scope, Status,
+};
+
+#[derive(Clone)]
+pub enum PinboardRoute {
+    PostsUpdate,
+    PostsAdd,
+    PostsDelete,
+    PostsGet,
+    PostsRecent,
+    PostsDates,
+    PostsAll,
+    TagsGet,
+    TagsDelete,
+    TagsRename,
+}
+
+impl From<PinboardRoute> for &'static str {
+    fn from(value: PinboardRoute) -> Self {
+        match value {
+            PinboardRoute::PostsUpdate => "/api/v1/posts/update",
+            PinboardRoute::PostsAdd => "/api/v1/posts/add",
+            PinboardRoute::PostsDelete => "/api/v1/posts/delete",
+            PinboardRoute::PostsGet => "/api/v1/posts/get",
+            PinboardRoute::PostsRecent => "/api/v1/posts/recent",
+            PinboardRoute::PostsDates => "/api/v1/posts/dates",
+            PinboardRoute::PostsAll => "/api/v1/posts/all",
+            PinboardRoute::TagsGet => "/api/v1/tags/get",
+            PinboardRoute::TagsDelete => "/api/v1/tags/delete",
+            PinboardRoute::TagsRename => "/api/v1/tags/rename",
+        }
+    }
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route(PinboardRoute::PostsUpdate.into(), get(posts_update))
+        .route(PinboardRoute::PostsAdd.into(), get(posts_add))
+        .route(PinboardRoute::PostsDelete.into(), get(posts_delete))
+        .route(PinboardRoute::PostsGet.into(), get(posts_get))
+        .route(PinboardRoute::PostsRecent.into(), get(posts_recent))
+        .route(PinboardRoute::PostsDates.into(), get(posts_dates))
+        .route(PinboardRoute::PostsAll.into(), get(posts_all))
+        .route(PinboardRoute::TagsGet.into(), get(tags_get))
+        .route(PinboardRoute::TagsDelete.into(), get(tags_delete))
+        .route(PinboardRoute::TagsRename.into(), get(tags_rename))
+}
+
+/// Extractor for the pinboard routes. Pinboard writes are GETs, which a
+/// session cookie would let any other site make, so only api tokens sign
+/// in here: from the `auth_token` query parameter, the way pinboard clients
+/// send it, or a bearer header.
+struct Pinboard(Context);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Pinboard {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = auth::query_token(parts)
+            .or_else(|| auth::api_token(parts))
+            .ok_or(Error::Unauthorized)?;
+        let cx = Context::for_token(parts, database().await?, &token).await?;
+        Ok(Pinboard(cx))
+    }
+}
+
+#[derive(Deserialize)]
+struct Format {
+    format: Option<String>,
+}
+
+impl Format {
+    fn json(&self) -> bool {
+        self.format.as_deref() == Some("json")
+    }
+}
+
+#[derive(Serialize)]
+struct Post {
+    href: String,
+    description: String,
+    extended: String,
+    meta: String,
+    hash: String,
+    time: String,
+    shared: &'static str,
+    toread: &'static str,
+    tags: String,
+}
+
+impl Post {
+    fn new(link: &Link, tags: &[String]) -> Self {
+        let description = link.display_title().to_owned();
+        let extended = link.notes.clone().unwrap_or_default();
+        let tags = tags.join(" ");
+        Self {
+            meta: md5_hex(&format!("{}{}{}", description, extended, tags)),
+            hash: md5_hex(&link.url),
+            href: link.url.clone(),
+            time: timestamp(link.created_at),
+            shared: "no",
+            toread: match link.status {
+                Status::Unread => "yes",
+                Status::Read | Status::Archived => "no",
+            },
+            description,
+            extended,
+            tags,
+        }
+    }
+
+    fn xml(&self) -> String {
+        format!(
+            "<post href=\"{}\" time=\"{}\" description=\"{}\" extended=\"{}\" tag=\"{}\" hash=\"{}\" meta=\"{}\" shared=\"{}\" toread=\"{}\" />",
+            escape(&self.href),
+            self.time,
+            escape(&self.description),
+            escape(&self.extended),
+            escape(&self.tags),
+            self.hash,
+            self.meta,
+            self.shared,
+            self.toread
+        )
+    }
+}
+
+/// Which links a posts endpoint wants. Every filter is optional.
+#[derive(Default)]
+struct Filter {
+    tags: Vec<String>,
+    url: Option<String>,
+    from: Option<f64>,
+    to: Option<f64>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+}
+
+impl Context {
+    async fn pinboard_links(&self, filter: &Filter) -> Res<Vec<Link>> {
+        let mut sql = String::from("select * from links where user_id = ?");
+        let mut values: Vec<rizz::Value> = vec![self.user.id.clone().into()];
+        for tag in &filter.tags {
+            sql.push_str(
+                " and exists (select 1 from link_tags join tags on tags.id = link_tags.tag_id
+                  where link_tags.link_id = links.id and tags.name = ?)",
+            );
+            values.push(tag.clone().into());
+        }
+        if let Some(url) = &filter.url {
+            sql.push_str(" and (normalized_url = ? or url = ?)");
+            values.push(normalize_url(url).into());
+            values.push(url.clone().into());
+        }
+        if let Some(from) = filter.from {
+            sql.push_str(" and created_at >= ?");
+            values.push(from.into());
+        }
+        if let Some(to) = filter.to {
+            sql.push_str(" and created_at < ?");
+            values.push(to.into());
+        }
+        sql.push_str(" order by created_at desc, id desc limit ? offset ?");
+        values.push(filter.limit.unwrap_or(-1).into());
+        values.push(filter.offset.unwrap_or(0).into());
+        let rows = self.db.query(&sql, values).all().await?;
+        Ok(rows)
+    }
+
+    async fn posts(&self, filter: &Filter) -> Res<Vec<Post>> {
+        let links = self.pinboard_links(filter).await?;
+        let mut tags = self.tags_for(&links).await?;
+        Ok(links
+            .iter()
+            .map(|link| Post::new(link, &tags.remove(&link.id).unwrap_or_default()))
+            .collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct Latest {
+    created_at: Option<f64>,
+}
+
+async fn posts_update(Pinboard(cx): Pinboard, Query(format): Query<Format>) -> Res<Response> {
+    let rows: Vec<Latest> = cx
+        .db
+        .query(
+            "select max(created_at) as created_at from links where user_id = ?",
+            vec![cx.user.id.clone().into()],
+        )
+        .all()
+        .await?;
+    let time = timestamp(rows.first().and_then(|row| row.created_at).unwrap_or(0.0));
+    Ok(match format.json() {
+        true => Json(json!({ "update_time": time })).into_response(),
+        false => xml(format!("<update time=\"{}\" />", time)),
+    })
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    url: String,
+    description: Option<String>,
+    extended: Option<String>,
+    tags: Option<String>,
+    dt: Option<String>,
+    replace: Option<String>,
+    toread: Option<String>,
+}
+
+async fn posts_add(
+    Pinboard(cx): Pinboard,
+    Query(format): Query<Format>,
+    Query(params): Query<AddParams>,
+) -> Res<Response> {
+    cx.require_scope(Scope::LinksWrite)?;
+    let url = params.url.trim().to_owned();
+    if validate_url(&url).is_err() {
+        return Ok(result(&format, "missing url"));
+    }
+    let created_at = match &params.dt {
+        Some(dt) => Some(parse_time(dt).ok_or(Error::BadRequest("invalid dt".into()))?),
+        None => None,
+    };
+    let title = params.description.and_then(non_empty);
+    let notes = params.extended.and_then(non_empty);
+    let tags = tag_names(&params.tags.unwrap_or_default());
+    let status = match params.toread.as_deref() {
+        Some("yes") => Some(Status::Unread),
+        Some("no") => Some(Status::Read),
+        _ => None,
+    };
+    match cx.link_by_url(&url).await? {
+        Some(_) if params.replace.as_deref() == Some("no") => {
+            Ok(result(&format, "item already exists"))
+        }
+        Some(mut link) => {
+            link.title = title;
+            link.notes = notes;
+            cx.save_link(&link).await?;
+            cx.set_tags(&link.id, &tags).await?;
+            if let Some(created_at) = created_at {
+                set_created_at(&cx, &link.id, created_at).await?;
+            }
+            if let Some(status) = status {
+                let _link = cx.set_status(link, status).await?;
+            }
+            Ok(result(&format, "done"))
+        }
+        None => {
+            let mut link = Link::new(&cx.user.id, url);
+            link.created_at = created_at.unwrap_or(link.created_at);
+            link.title = title;
+            link.notes = notes;
+            link.status = status.unwrap_or_default();
+            if link.status != Status::Unread {
+                link.read_at = Some(link.created_at);
+            }
+            let _link = cx.create_link(link, &tags).await?;
+            Ok(result(&format, "done"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UrlParams {
+    url: String,
+}
+
+async fn posts_delete(
+    Pinboard(cx): Pinboard,
+    Query(format): Query<Format>,
+    Query(params): Query<UrlParams>,
+) -> Res<Response> {
+    cx.require_scope(Scope::LinksWrite)?;
+    match cx.link_by_url(params.url.trim()).await? {
+        Some(link) => {
+            cx.delete_link(&link.id).await?;
+            Ok(result(&format, "done"))
+        }
+        None => Ok(result(&format, "item not found")),
+    }
+}
+
+#[derive(Deserialize)]
+struct GetParams {
+    tag: Option<String>,
+    dt: Option<String>,
+    url: Option<String>,
+}
+
+async fn posts_get(
+    Pinboard(cx): Pinboard,
+    Query(format): Query<Format>,
+    Query(params): Query<GetParams>,
+) -> Res<Response> {
+    let mut filter = Filter {
+        tags: first_tags(params.tag.as_deref()),
+        ..Default::default()
+    };
+    match (&params.url, &params.dt) {
+        (Some(url), _) => filter.url = Some(url.trim().to_owned()),
+        (None, Some(dt)) => {
+            let day = parse_date(dt).ok_or(Error::BadRequest("invalid dt".into()))?;
+            filter.from = Some(day);
+            filter.to = Some(day + 86_400.0);
+        }
+        (None, None) => {
+            let latest = cx
+                .pinboard_links(&Filter {
+                    tags: filter.tags.clone(),
+                    limit: Some(1),
+                    ..Default::default()
+                })
+                .await?;
+            if let Some(link) = latest.first() {
+                let day = (link.created_at / 86_400.0).floor() * 86_400.0;
+                filter.from = Some(day);
+                filter.to = Some(day + 86_400.0);
+            }
+        }
+    }
+    let posts = cx.posts(&filter).await?;
+    let date = filter.from.map(timestamp).unwrap_or_default();
+    Ok(posts_response(
+        &cx,
+        &format,
+        &date,
+        params.tag.as_deref(),
+        posts,
+    ))
+}
+
+#[derive(Deserialize)]
+struct RecentParams {
+    tag: Option<String>,
+    count: Option<i64>,
+}
+
+async fn posts_recent(
+    Pinboard(cx): Pinboard,
+    Query(format): Query<Format>,
+    Query(params): Query<RecentParams>,
+) -> Res<Response> {
+    let filter = Filter {
+        tags: first_tags(params.tag.as_deref()),
+        limit: Some(params.count.unwrap_or(15).clamp(1, 100)),
+        ..Default::default()
+    };
+    let posts = cx.posts(&filter).await?;
+    let date = timestamp(now());
+    Ok(posts_response(
+        &cx,
+        &format,
+        &date,
+        params.tag.as_deref(),
+        posts,
+    ))
+}
+
+#[derive(Deserialize)]
+struct DateCount {
+    date: String,
+    count: i64,
+}
+
+async fn posts_dates(
+    Pinboard(cx): Pinboard,
+    Query(format): Query<Format>,
+    Query(params): Query<RecentParams>,
+) -> Res<Response> {
+    let links = cx
+        .pinboard_links(&Filter {
+            tags: first_tags(params.tag.as_deref()),
+            ..Default::default()
+        })
+        .await?;
+    let mut dates: Vec<DateCount> = vec![];
+    for link in links {
+        let date = timestamp(link.created_at)
+            .get(..10)
+            .unwrap_or_default()
+            .to_owned();
+        match dates.last_mut() {
+            Some(last) if last.date == date => last.count += 1,
+            _ => dates.push(DateCount { date, count: 1 }),
+        }
+    }
+    let user = username(&cx);
+    let tag = params.tag.unwrap_or_default();
+    Ok(match format.json() {
+        true => {
+            let counts: serde_json::Map<String, serde_json::Value> = dates
+                .iter()
+                .map(|date| (date.date.clone(), json!(date.count.to_string())))
+                .collect();
+            Json(json!({ "user": user, "tag": tag, "dates": counts })).into_response()
+        }
+        false => {
+            let body: String = dates
+                .iter()
+                .map(|date| format!("<date count=\"{}\" date=\"{}\" />", date.count, date.date))
+                .collect();
+            xml(format!(
+                "<dates tag=\"{}\" user=\"{}\">{}</dates>",
+                escape(&tag),
+                escape(&user),
+                body
+            ))
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct AllParams {
+    tag: Option<String>,
+    start: Option<i64>,
+    results: Option<i64>,
+    fromdt: Option<String>,
+    todt: Option<String>,
+}
+
+async fn posts_all(
+    Pinboard(cx): Pinboard,
+    Query(format): Query<Format>,
+    Query(params): Query<AllParams>,
+) -> Res<Response> {
+    let filter = Filter {
+        tags: first_tags(params.tag.as_deref()),
+        offset: params.start,
+        limit: params.results,
+        from: params.fromdt.as_deref().and_then(parse_time),
+        to: params.todt.as_deref().and_then(parse_time),
+        ..Default::default()
+    };
+    let posts = cx.posts(&filter).await?;
+    Ok(match format.json() {
+        true => Json(posts).into_response(),
+        false => {
+            let body: String = posts.iter().map(Post::xml).collect();
+            xml(format!(
+                "<posts user=\"{}\" tag=\"{}\">{}</posts>",
+                escape(&username(&cx)),
+                escape(params.tag.as_deref().unwrap_or_default()),
+                body
+            ))
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct TagCount {
+    name: String,
+    count: i64,
+}
+
+async fn tags_get(Pinboard(cx): Pinboard, Query(format): Query<Format>) -> Res<Response> {
+    let tags: Vec<TagCount> = cx
+        .db
+        .query(
+            "select tags.name, count(*) as count from tags
+             join link_tags on link_tags.tag_id = tags.id
+             join links on links.id = link_tags.link_id
+             where links.user_id = ?
+             group by tags.name
+             order by tags.name",
+            vec![cx.user.id.clone().into()],
+        )
+        .all()
+        .await?;
+    Ok(match format.json() {
+        true => {
+            let counts: serde_json::Map<String, serde_json::Value> = tags
+                .into_iter()
+                .map(|tag| (tag.name, json!(tag.count)))
+                .collect();
+            Json(counts).into_response()
+        }
+        false => {
+            let body: String = tags
+                .iter()
+                .map(|tag| {
+                    format!(
+                        "<tag count=\"{}\" tag=\"{}\" />",
+                        tag.count,
+                        escape(&tag.name)
+                    )
+                })
+                .collect();
+            xml(format!("<tags>{}</tags>", body))
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct TagParams {
+    tag: String,
+}
+
+async fn tags_delete(
+    Pinboard(cx): Pinboard,
+    Query(format): Query<Format>,
+    Query(params): Query<TagParams>,
+) -> Res<Response> {
+    cx.require_scope(Scope::LinksWrite)?;
+    untag(&cx, &params.tag.trim().to_lowercase()).await?;
+    Ok(result(&format, "done"))
+}
+
+#[derive(Deserialize)]
+struct RenameParams {
+    old: String,
+    new: String,
+}
+
+async fn tags_rename(
+    Pinboard(cx): Pinboard,
+    Query(format): Query<Format>,
+    Query(params): Query<RenameParams>,
+) -> Res<Response> {
+    cx.require_scope(Scope::LinksWrite)?;
+    let old = params.old.trim().to_lowercase();
+    let new = tag_names(&params.new);
+    for link in cx.tagged_links(&old).await? {
+        cx.tag_link(&link.id, &new).await?;
+    }
+    untag(&cx, &old).await?;
+    Ok(result(&format, "done"))
+}
+
+/// Removes a tag from every one of the user's links.
+async fn untag(cx: &Context, name: &str) -> Res<()> {
+    let _rows_affected = cx
+        .db
+        .execute(
+            "delete from link_tags
+             where tag_id in (select id from tags where name = ?)
+             and link_id in (select id from links where user_id = ?)",
+            vec![name.into(), cx.user.id.clone().into()],
+        )
+        .await?;
+    Ok(())
+}
+
+async fn set_created_at(cx: &Context, id: &str, created_at: f64) -> Res<()> {
+    let _rows_affected = cx
+        .db
+        .execute(
+            "update links set created_at = ? where id = ? and user_id = ?",
+            vec![created_at.into(), id.into(), cx.user.id.clone().into()],
+        )
+        .await?;
+    Ok(())
+}
+
+fn posts_response(
+    cx: &Context,
+    format: &Format,
+    date: &str,
+    tag: Option<&str>,
+    posts: Vec<Post>,
+) -> Response {
+    let user = username(cx);
+    let tag = tag.unwrap_or_default();
+    match format.json() {
+        true => Json(json!({ "date": date, "user": user, "posts": posts })).into_response(),
+        false => {
+            let body: String = posts.iter().map(Post::xml).collect();
+            xml(format!(
+                "<posts dt=\"{}\" tag=\"{}\" user=\"{}\">{}</posts>",
+                date,
+                escape(tag),
+                escape(&user),
+                body
+            ))
+        }
+    }
+}
+
+fn result(format: &Format, code: &str) -> Response {
+    match format.json() {
+        true => Json(json!({ "result_code": code })).into_response(),
+        false => xml(format!("<result code=\"{}\" />", escape(code))),
+    }
+}
+
+fn xml(body: String) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/xml; charset=utf-8")],
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n{}", body),
+    )
+        .into_response()
+}
+
+/// Pinboard filters by at most three tags.
+fn first_tags(tags: Option<&str>) -> Vec<String> {
+    tag_names(tags.unwrap_or_default())
+        .into_iter()
+        .take(3)
+        .collect()
+}
+
+fn username(cx: &Context) -> String {
+    cx.user
+        .email
+        .split('@')
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+fn timestamp(time: f64) -> String {
+    chrono::DateTime::from_timestamp(time as i64, 0)
+        .map(|datetime| datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_default()
+}
+
+fn parse_time(value: &str) -> Option<f64> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|datetime| datetime.timestamp() as f64)
+        .or_else(|| parse_date(value))
+}
+
+fn parse_date(value: &str) -> Option<f64> {
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp() as f64)
+}
+
+fn md5_hex(value: &str) -> String {
+    format!("{:x}", md5::compute(value.as_bytes()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,605 @@
+//! Reader mode: pulls the article out of a saved page, readability style,
+//! and shows it as plain, comfortable text.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use maud::{html, Markup, PreEscaped};
+use scraper::{node::Node, ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{date, now, Component, Context, Error, Link, Res};
+
+/// Average adult silent reading speed, in words per minute.
+const WORDS_PER_MINUTE: i64 = 238;
+
+/// Words in the classes and ids of things around an article rather than
+/// in it.
+const UNLIKELY: &[&str] = &[
+    "ad",
+    "ads",
+    "advert",
+    "advertisement",
+    "banner",
+    "breadcrumb",
+    "breadcrumbs",
+    "comment",
+    "comments",
+    "cookie",
+    "cookies",
+    "disqus",
+    "footer",
+    "header",
+    "masthead",
+    "menu",
+    "modal",
+    "nav",
+    "navbar",
+    "navigation",
+    "newsletter",
+    "popup",
+    "promo",
+    "related",
+    "share",
+    "sidebar",
+    "social",
+    "sponsor",
+    "sponsored",
+    "subscribe",
+];
+
+const LIKELY: &[&str] = &[
+    "article", "body", "content", "entry", "main", "post", "story", "text",
+];
+
+/// Elements dropped from extracted content, along with everything inside them.
+const DROP: &[&str] = &[
+    "aside", "button", "canvas", "embed", "footer", "form", "header", "iframe", "input", "nav",
+    "noscript", "object", "script", "select", "style", "svg", "textarea",
+];
+
+/// Elements kept as they are; anything else is unwrapped to its contents.
+const KEEP: &[&str] = &[
+    "a",
+    "b",
+    "blockquote",
+    "br",
+    "code",
+    "dd",
+    "dl",
+    "dt",
+    "em",
+    "figcaption",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "ul",
+];
+
+#[derive(Clone)]
+pub enum ReaderRoute {
+    Read,
+}
+
+impl std::fmt::Display for ReaderRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let x: &str = self.to_owned().into();
+        f.write_str(x)
+    }
+}
+
+impl From<ReaderRoute> for &'static str {
+    fn from(value: ReaderRoute) -> Self {
+        match value {
+            ReaderRoute::Read => "/links/:id/read",
+        }
+    }
+}
+
+pub fn routes() -> Router {
+    Router::new().route(ReaderRoute::Read.into(), get(read))
+}
+
+pub fn read_url(id: &str) -> String {
+    let route: &str = ReaderRoute::Read.into();
+    route.replace(":id", id)
+}
+
+struct ReaderComponent {
+    link: Link,
+    article: Option<Article>,
+}
+
+impl Component for ReaderComponent {
+    fn html(&self) -> Markup {
+        let link = &self.link;
+        html! {
+            link rel="stylesheet" href="/pub/reader.css";
+            article class="reader" {
+                h1 { (link.display_title()) }
+                @match &self.article {
+                    Some(article) => {
+                        p class="reader-meta" {
+                            @if let Some(byline) = &article.byline {
+                                (byline) " · "
+                            }
+                            @if let Some(published_at) = article.published_at {
+                                (date(published_at)) " · "
+                            }
+                            (article.reading_time) " min read · " (article.word_count) " words · "
+                            a href=(link.url) { "original" }
+                        }
+                        (PreEscaped(&article.content))
+                    }
+                    None => {
+                        p class="reader-meta" {
+                            "Couldn't find an article on this page. "
+                            a href=(link.url) { "Read the original" } "."
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn read(cx: Context, Path(id): Path<String>) -> Res<Response> {
+    let link = cx.link(&id).await?;
+    let article = match cx.article(&link.id).await? {
+        Some(article) => Some(article),
+        None => cx.read_link(&link.id, &link.url).await?,
+    };
+    if cx.json {
+        return Ok(Json(article).into_response());
+    }
+
+    Ok(cx
+        .render(ReaderComponent { link, article })?
+        .into_response())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Article {
+    /// Cleaned up html of the article body.
+    pub content: String,
+    pub byline: Option<String>,
+    pub published_at: Option<f64>,
+    pub word_count: i64,
+    /// In minutes.
+    pub reading_time: i64,
+}
+
+impl Context {
+    /// Fetches a link's page, extracts its article and stores it.
+    pub async fn read_link(&self, link_id: &str, url: &str) -> Res<Option<Article>> {
+        let page = self.fetcher.fetch(url).await?;
+        if !page.is_success() {
+            return Err(Error::Fetch(format!("{} returned {}", url, page.status)));
+        }
+        let Some(article) = extract(&page.text(), &page.url) else {
+            return Ok(None);
+        };
+        let _rows_affected = self
+            .db
+            .execute(
+                "insert into articles (link_id, content, byline, published_at, word_count, reading_time, extracted_at)
+                 values (?, ?, ?, ?, ?, ?, ?)
+                 on conflict (link_id) do update set content = excluded.content, byline = excluded.byline,
+                 published_at = excluded.published_at, word_count = excluded.word_count,
+                 reading_time = excluded.reading_time, extracted_at = excluded.extracted_at",
+                vec![
+                    link_id.into(),
+                    article.content.clone().into(),
+                    article.byline.clone().into(),
+                    article.published_at.into(),
+                    article.word_count.into(),
+                    article.reading_time.into(),
+                    now().into(),
+                ],
+            )
+            .await?;
+        Ok(Some(article))
+    }
+
+    async fn article(&self, link_id: &str) -> Res<Option<Article>> {
+        let rows: Vec<Article> = self
+            .db
+            .query(
+                "select content, byline, published_at, word_count, reading_time from articles where link_id = ?",
+                vec![link_id.into()],
+            )
+            .all()
+            .await?;
+        Ok(rows.into_iter().next())
+    }
+}
+
+/// Finds the main article in a page. Paragraphs score points for their
+/// parents and grandparents, and the best scoring element (along with any
+/// siblings that look like more of the same) is taken as the article.
+pub fn extract(html: &str, url: &str) -> Option<Article> {
+    let document = Html::parse_document(html);
+    let base = Url::parse(url).ok();
+    let root = best_candidate(&document)?;
+    let mut content = String::new();
+    let mut text = String::new();
+    let mut cleaner = Cleaner {
+        base: base.as_ref(),
+        content: &mut content,
+        text: &mut text,
+    };
+    for element in with_siblings(&root) {
+        cleaner.clean(element);
+    }
+    let word_count = text.split_whitespace().count() as i64;
+    if word_count == 0 {
+        return None;
+    }
+
+    Some(Article {
+        content,
+        byline: byline(&document),
+        published_at: published_at(&document),
+        word_count,
+        reading_time: ((word_count + WORDS_PER_MINUTE - 1) / WORDS_PER_MINUTE).max(1),
+    })
+}
+
+fn best_candidate(document: &Html) -> Option<ElementRef<'_>> {
+    let paragraphs = Selector::parse("p, pre, td, blockquote").ok()?;
+    let mut scores = HashMap::new();
+    for paragraph in document.select(&paragraphs) {
+        if excluded(&paragraph) {
+            continue;
+        }
+        let text = paragraph.text().collect::<String>();
+        let text = text.trim();
+        if text.chars().count() < 25 {
+            continue;
+        }
+        let score = 1.0 + text.matches(',').count() as f64 + (text.len() as f64 / 100.0).min(3.0);
+        let ancestors = paragraph.ancestors().filter_map(ElementRef::wrap).take(2);
+        for (level, ancestor) in ancestors.enumerate() {
+            let (_, entry) = scores
+                .entry(ancestor.id())
+                .or_insert_with(|| (ancestor, initial_score(&ancestor)));
+            *entry += score / (level + 1) as f64;
+        }
+    }
+    let best = scores
+        .into_values()
+        .map(|(element, score)| (element, score * (1.0 - link_density(&element))))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(element, _)| element);
+
+    best.or_else(|| {
+        let fallback = Selector::parse("article, main, body").ok()?;
+        document.select(&fallback).next()
+    })
+}
+
+fn initial_score(element: &ElementRef) -> f64 {
+    let tag = match element.value().name() {
+        "article" => 10.0,
+        "div" | "main" | "section" => 5.0,
+        "blockquote" | "pre" | "td" => 3.0,
+        "dd" | "dl" | "dt" | "li" | "ol" | "ul" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    };
+    tag + class_weight(element)
+}
+
+/// Scores an element on the words in its classes and id, split on `-` and
+/// `_`. Only whole words count, so `lead-in` isn't taken for an ad.
+fn class_weight(element: &ElementRef) -> f64 {
+    let value = element.value();
+    let names = format!(
+        "{} {}",
+        value.attr("class").unwrap_or_default(),
+        value.id().unwrap_or_default()
+    )
+    .to_lowercase();
+    let words: Vec<&str> = names
+        .split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+        .filter(|word| !word.is_empty())
+        .collect();
+    let mut weight = 0.0;
+    if UNLIKELY.iter().any(|name| words.contains(name)) {
+        weight -= 25.0;
+    }
+    if LIKELY.iter().any(|name| words.contains(name)) {
+        weight += 25.0;
+    }
+    weight
+}
+
+/// Paragraphs inside page furniture don't count towards anything. The
+/// walk up stops short of `<body>`, whose classes describe the whole page.
+fn excluded(element: &ElementRef) -> bool {
+    element
+        .ancestors()
+        .filter_map(ElementRef::wrap)
+        .take_while(|ancestor| ancestor.value().name() != "body")
+        .any(|ancestor| DROP.contains(&ancestor.value().name()) || class_weight(&ancestor) < 0.0)
+}
+
+/// How much of an element's text is links, from 0 to 1.
+fn link_density(element: &ElementRef) -> f64 {
+    let length = element.text().map(str::len).sum::<usize>();
+    if length == 0 {
+        return 1.0;
+    }
+    let links = Selector::parse("a").expect("valid selector");
+    let link_length = element
+        .select(&links)
+        .flat_map(|link| link.text())
+        .map(str::len)
+        .sum::<usize>();
+    link_length as f64 / length as f64
+}
+
+/// The chosen element, plus neighbouring paragraphs that are part of the
+/// same article but live outside it.
+fn with_siblings<'a>(element: &ElementRef<'a>) -> Vec<ElementRef<'a>> {
+    let Some(parent) = element.parent() else {
+        return vec![*element];
+    };
+    parent
+        .children()
+        .filter_map(ElementRef::wrap)
+        .filter(|sibling| {
+            if sibling == element {
+                return true;
+            }
+            let text = sibling.text().collect::<String>();
+            sibling.value().name() == "p"
+                && text.trim().len() > 80
+                && link_density(sibling) < 0.25
+                && class_weight(sibling) >= 0.0
+        })
+        .collect()
+}
+
+/// Writes an element back out with only the tags and attributes that
+/// matter for reading, collecting its plain text on the way.
+struct Cleaner<'a> {
+    base: Option<&'a Url>,
+    content: &'a mut String,
+    text: &'a mut String,
+}
+
+impl<'a> Cleaner<'a> {
+    fn clean(&mut self, element: ElementRef) {
+        let value = element.value();
+        let name = value.name();
+        if DROP.contains(&name) || (name != "body" && class_weight(&element) < 0.0) {
+            return;
+        }
+        let keep = KEEP.contains(&name);
+        if keep {
+            self.content.push('<');
+            self.content.push_str(name);
+            match name {
+                "a" => {
+                    if let Some(href) = value.attr("href").and_then(|href| self.absolute(href)) {
+                        self.attr("href", &href);
+                    }
+                }
+                "img" => {
+                    let src = value.attr("src").or_else(|| value.attr("data-src"));
+                    if let Some(src) = src.and_then(|src| self.absolute(src)) {
+                        self.attr("src", &src);
+                    }
+                    self.attr("alt", value.attr("alt").unwrap_or_default());
+                    self.attr("loading", "lazy");
+                }
+                _ => {}
+            }
+            self.content.push('>');
+        }
+        if matches!(name, "br" | "hr" | "img") {
+            return;
+        }
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => {
+                    self.content.push_str(&escape(text));
+                    self.text.push_str(text);
+                    self.text.push(' ');
+                }
+                Node::Element(_) => {
+                    if let Some(child) = ElementRef::wrap(child) {
+                        self.clean(child);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if keep {
+            self.content.push_str("</");
+            self.content.push_str(name);
+            self.content.push('>');
+        }
+    }
+
+    fn attr(&mut self, name: &str, value: &str) {
+        self.content
+            .push_str(&format!(" {}=\"{}\"", name, escape(value)));
+    }
+
+    /// Only http(s) urls survive, so nothing like `javascript:` gets through.
+    fn absolute(&self, value: &str) -> Option<String> {
+        let url = match self.base {
+            Some(base) => base.join(value.trim()).ok()?,
+            None => Url::parse(value.trim()).ok()?,
+        };
+        match url.scheme() {
+            "http" | "https" => Some(url.to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn byline(document: &Html) -> Option<String> {
+    let candidates = [
+        ("meta[name=author]", Some("content")),
+        ("meta[property='article:author']", Some("content")),
+        ("[itemprop=author] [itemprop=name]", None),
+        ("[itemprop=author]", None),
+        ("[rel=author]", None),
+        (".byline", None),
+        (".author", None),
+    ];
+    candidates.into_iter().find_map(|(selector, attr)| {
+        let selector = Selector::parse(selector).ok()?;
+        let element = document.select(&selector).next()?;
+        let value = match attr {
+            Some(attr) => element.value().attr(attr)?.to_owned(),
+            None => element.text().collect::<String>(),
+        };
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        match value.is_empty() || value.len() > 100 || value.starts_with("http") {
+            true => None,
+            false => Some(value),
+        }
+    })
+}
+
+fn published_at(document: &Html) -> Option<f64> {
+    let candidates = [
+        ("meta[property='article:published_time']", "content"),
+        ("meta[itemprop=datePublished]", "content"),
+        ("[itemprop=datePublished]", "datetime"),
+        ("meta[name=date]", "content"),
+        ("time[datetime]", "datetime"),
+    ];
+    candidates.into_iter().find_map(|(selector, attr)| {
+        let selector = Selector::parse(selector).ok()?;
+        let value = document.select(&selector).next()?.value().attr(attr)?;
+        parse_date(value.trim())
+    })
+}
+
+/// Accepts full timestamps or plain `YYYY-MM-DD` dates.
+fn parse_date(value: &str) -> Option<f64> {
+    if let Ok(datetime) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(datetime.timestamp() as f64);
+    }
+    let day = value.get(..10)?;
+    chrono::DateTime::parse_from_rfc3339(&format!("{}T00:00:00Z", day))
+        .ok()
+        .map(|datetime| datetime.timestamp() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAGRAPH: &str = "Reader mode pulls the article out of a page, along with a byline, a date and an estimate of how long it takes to read.";
+
+    fn page(body: &str) -> String {
+        format!(
+            r#"<html><head>
+            <meta name="author" content="Ada Lovelace">
+            <meta property="article:published_time" content="2023-11-02T10:00:00Z">
+            </head>{}</html>"#,
+            body
+        )
+    }
+
+    #[test]
+    fn finds_the_article_and_drops_the_rest() {
+        let html = page(&format!(
+            r#"<body>
+            <nav><p>{p}</p></nav>
+            <div class="sidebar"><p>Sidebar, with, lots, of, commas, that, would, score, well, otherwise.</p></div>
+            <article><h1>Title</h1><p>{p}</p><p>{p} <a href="/more">More</a><script>alert(1)</script></p></article>
+            <footer><p>{p}</p></footer>
+            </body>"#,
+            p = PARAGRAPH
+        ));
+        let article = extract(&html, "https://example.com/posts/1").unwrap();
+        assert_eq!(article.content.matches(PARAGRAPH).count(), 2);
+        assert!(!article.content.contains("Sidebar"));
+        assert!(!article.content.contains("script"));
+        assert!(article
+            .content
+            .contains(r#"<a href="https://example.com/more">More</a>"#));
+        assert_eq!(article.byline.as_deref(), Some("Ada Lovelace"));
+        assert_eq!(article.published_at, Some(1698919200.0));
+        assert_eq!(article.reading_time, 1);
+    }
+
+    #[test]
+    fn ignores_classes_on_body() {
+        let html = page(&format!(
+            r#"<body class="has-sidebar nav-open"><div><p>{p}</p><p>{p}</p></div></body>"#,
+            p = PARAGRAPH
+        ));
+        let article = extract(&html, "https://example.com").unwrap();
+        assert_eq!(article.content.matches(PARAGRAPH).count(), 2);
+    }
+
+    #[test]
+    fn matches_whole_class_words() {
+        let html = page(&format!(
+            r#"<body><div class="lead-in thread-body"><p>{p}</p><p>{p}</p></div>
+            <div class="ad-slot"><p>Buy things, now, today, while, they, last, and, more, commas.</p></div></body>"#,
+            p = PARAGRAPH
+        ));
+        let article = extract(&html, "https://example.com").unwrap();
+        assert_eq!(article.content.matches(PARAGRAPH).count(), 2);
+        assert!(!article.content.contains("Buy things"));
+    }
+
+    #[test]
+    fn pages_without_text_have_no_article() {
+        assert!(extract(
+            "<html><body><img src=a.png></body></html>",
+            "https://example.com"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("2023-11-02"), Some(1698883200.0));
+        assert_eq!(parse_date("2023-11-02T10:00:00+02:00"), Some(1698912000.0));
+        assert_eq!(parse_date("last tuesday"), None);
+    }
+}
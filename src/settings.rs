@@ -0,0 +1,222 @@
+//! Account settings: bookmarklets and personal api tokens.
+
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Router,
+};
+use maud::{html, Markup};
+use serde::Deserialize;
+
+use crate::{
+    auth::{self, ApiToken, Scope},
+    button, config, date, now, text_field, Component, Context, Error, Html, Res, Route,
+};
+
+#[derive(Clone)]
+pub enum SettingsRoute {
+    Settings,
+    Tokens,
+    RevokeToken,
+}
+
+impl std::fmt::Display for SettingsRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let x: &str = self.to_owned().into();
+        f.write_str(x)
+    }
+}
+
+impl From<SettingsRoute> for &'static str {
+    fn from(value: SettingsRoute) -> Self {
+        match value {
+            SettingsRoute::Settings => "/settings",
+            SettingsRoute::Tokens => "/settings/tokens",
+            SettingsRoute::RevokeToken => "/settings/tokens/:id/revoke",
+        }
+    }
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route(SettingsRoute::Settings.into(), get(settings))
+        .route(SettingsRoute::Tokens.into(), post(create_token))
+        .route(SettingsRoute::RevokeToken.into(), post(revoke_token))
+}
+
+struct SettingsComponent {
+    tokens: Vec<ApiToken>,
+    created: Option<String>,
+}
+
+impl Component for SettingsComponent {
+    fn html(&self) -> Markup {
+        html! {
+            h2 class="text-xl" { "Bookmarklets" }
+            p class="text-gray-500" {
+                "Drag one of these to your bookmarks bar, then click it on any page to save it."
+            }
+            div class="flex gap-4" {
+                a class="px-3 py-2 rounded-md bg-orange-500 hover:bg-orange-400" href=(bookmarklet(false)) { "save to links" }
+                a class="px-3 py-2 rounded-md bg-orange-500 hover:bg-orange-400" href=(bookmarklet(true)) { "save to links (popup)" }
+            }
+            h2 class="text-xl" { "API tokens" }
+            p class="text-gray-500" {
+                "Send a token as " code { "Authorization: Bearer <token>" } " to use the api from scripts."
+            }
+            @if let Some(token) = &self.created {
+                div class="flex flex-col gap-2 p-3 rounded-md bg-orange-100 dark:bg-orange-900" {
+                    p { "Copy your new token now, it won't be shown again." }
+                    code class="break-all select-all" { (token) }
+                }
+            }
+            form class="flex flex-col w-full gap-3" action=(SettingsRoute::Tokens) method="post" hx-ext="ignore:json-enc" {
+                (text_field("name", "token name, e.g. ci"))
+                div class="flex gap-4" {
+                    @for scope in Scope::ALL {
+                        label class="flex items-center gap-2" {
+                            input type="checkbox" name=(scope_field(scope)) value="on" checked[scope == Scope::LinksRead];
+                            (scope.as_str())
+                        }
+                    }
+                }
+                (button("Create token"))
+            }
+            div class="w-full flex flex-col gap-4 divide-y dark:divide-gray-700 divide-gray-200" {
+                @for token in &self.tokens {
+                    div class="flex justify-between items-center gap-3 pt-4" {
+                        div class="flex flex-col" {
+                            span class="text-lg" { (token.name) }
+                            span class="text-sm text-gray-500" {
+                                (token.scopes) " · created " (date(token.created_at)) " · "
+                                @match token.last_used_at {
+                                    Some(used) => { "last used " (date(used)) }
+                                    None => { "never used" }
+                                }
+                            }
+                        }
+                        @match token.revoked_at {
+                            Some(revoked) => {
+                                span class="text-sm text-gray-500" { "revoked " (date(revoked)) }
+                            }
+                            None => {
+                                form action=(revoke_url(&token.id)) method="post" {
+                                    button type="submit" class="text-sm hover:text-red-500" { "revoke" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A javascript: url that opens the quick add form for the current page,
+/// either in place or in a small popup window.
+fn bookmarklet(popup: bool) -> String {
+    let add = format!(
+        "'{}{}?url='+encodeURIComponent(location.href)+'&title='+encodeURIComponent(document.title)",
+        config().base_url,
+        Route::QuickAdd
+    );
+    match popup {
+        true => format!(
+            "javascript:void(window.open({}+'&popup=1','links','width=520,height=480'))",
+            add
+        ),
+        false => format!("javascript:void(location.href={})", add),
+    }
+}
+
+fn scope_field(scope: Scope) -> &'static str {
+    match scope {
+        Scope::LinksRead => "links_read",
+        Scope::LinksWrite => "links_write",
+    }
+}
+
+fn revoke_url(id: &str) -> String {
+    let route: &str = SettingsRoute::RevokeToken.into();
+    route.replace(":id", id)
+}
+
+impl Context {
+    /// Tokens can't be used to manage tokens.
+    fn require_session(&self) -> Res<()> {
+        match self.token {
+            Some(_) => Err(Error::Forbidden),
+            None => Ok(()),
+        }
+    }
+
+    async fn tokens(&self) -> Res<Vec<ApiToken>> {
+        let rows = self
+            .db
+            .query(
+                "select * from api_tokens where user_id = ? order by revoked_at is not null, created_at desc",
+                vec![self.user.id.clone().into()],
+            )
+            .all()
+            .await?;
+        Ok(rows)
+    }
+}
+
+async fn settings(cx: Context) -> Html {
+    cx.require_session()?;
+    let tokens = cx.tokens().await?;
+
+    cx.render(SettingsComponent {
+        tokens,
+        created: None,
+    })
+}
+
+#[derive(Deserialize)]
+struct TokenParams {
+    name: String,
+    links_read: Option<String>,
+    links_write: Option<String>,
+}
+
+async fn create_token(cx: Context, Form(params): Form<TokenParams>) -> Html {
+    cx.require_session()?;
+    let name = params.name.trim();
+    if name.is_empty() {
+        return Err(Error::Validation("Tokens need a name".into()));
+    }
+    let scopes: Vec<Scope> = Scope::ALL
+        .into_iter()
+        .filter(|scope| match scope {
+            Scope::LinksRead => params.links_read.is_some(),
+            Scope::LinksWrite => params.links_write.is_some(),
+        })
+        .collect();
+    if scopes.is_empty() {
+        return Err(Error::Validation("Pick at least one scope".into()));
+    }
+    let token = auth::create_token(&cx.db, &cx.user.id, name, &scopes).await?;
+    let tokens = cx.tokens().await?;
+
+    cx.render(SettingsComponent {
+        tokens,
+        created: Some(token),
+    })
+}
+
+async fn revoke_token(cx: Context, Path(id): Path<String>) -> Res<impl IntoResponse> {
+    cx.require_session()?;
+    let rows_affected = cx
+        .db
+        .execute(
+            "update api_tokens set revoked_at = ? where id = ? and user_id = ? and revoked_at is null",
+            vec![now().into(), id.into(), cx.user.id.clone().into()],
+        )
+        .await?;
+    if rows_affected == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(Redirect::to(SettingsRoute::Settings.into()))
+}
@@ -0,0 +1,289 @@
+//! WARC 1.1 output, so captures can be replayed with standard web archive
+//! tools. Each record is compressed as its own gzip member, which is what
+//! readers of `.warc.gz` files expect.
+
+use std::io::Write;
+
+use axum::http::StatusCode;
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{Fetched, Res};
+
+#[derive(Default)]
+pub struct Warc {
+    bytes: Vec<u8>,
+}
+
+impl Warc {
+    /// Describes the file itself; goes first.
+    pub fn warcinfo(&mut self, filename: &str, date: f64) -> Res<()> {
+        let block = format!(
+            "software: links/{}\r\nformat: WARC File Format 1.1\r\nconformsTo: http://iipc.github.io/warc-specifications/specifications/warc-format/warc-1.1/\r\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        self.record(
+            &[
+                ("WARC-Type", "warcinfo"),
+                ("WARC-Record-ID", &record_id(&nanoid::nanoid!())),
+                ("WARC-Date", &warc_date(date)),
+                ("WARC-Filename", filename),
+                ("Content-Type", "application/warc-fields"),
+            ],
+            block.as_bytes(),
+        )
+    }
+
+    /// Writes a response record and the request that produced it. `key`
+    /// must be unique to this exchange, across recaptures too; record ids
+    /// are derived from it so exporting the same capture twice gives the
+    /// same ids.
+    pub fn exchange(&mut self, key: &str, date: f64, fetched: &Fetched) -> Res<()> {
+        let response_id = record_id(&format!("{}:response", key));
+        let request_id = record_id(&format!("{}:request", key));
+        let date = warc_date(date);
+        self.record(
+            &[
+                ("WARC-Type", "response"),
+                ("WARC-Record-ID", &response_id),
+                ("WARC-Date", &date),
+                ("WARC-Target-URI", &fetched.url),
+                ("Content-Type", "application/http;msgtype=response"),
+            ],
+            &response_block(fetched),
+        )?;
+        self.record(
+            &[
+                ("WARC-Type", "request"),
+                ("WARC-Record-ID", &request_id),
+                ("WARC-Date", &date),
+                ("WARC-Target-URI", &fetched.url),
+                ("WARC-Concurrent-To", &response_id),
+                ("Content-Type", "application/http;msgtype=request"),
+            ],
+            &request_block(fetched),
+        )
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn record(&mut self, fields: &[(&str, &str)], block: &[u8]) -> Res<()> {
+        let mut record = Vec::with_capacity(block.len() + 512);
+        record.extend_from_slice(b"WARC/1.1\r\n");
+        for (name, value) in fields {
+            record.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        record.extend_from_slice(format!("Content-Length: {}\r\n\r\n", block.len()).as_bytes());
+        record.extend_from_slice(block);
+        record.extend_from_slice(b"\r\n\r\n");
+        let mut encoder = GzEncoder::new(&mut self.bytes, Compression::default());
+        encoder.write_all(&record)?;
+        let _bytes = encoder.finish()?;
+        Ok(())
+    }
+}
+
+/// The response as it came over the wire, except the body is stored
+/// decoded and whole, so the headers describing the encoding go.
+fn response_block(fetched: &Fetched) -> Vec<u8> {
+    let reason = StatusCode::from_u16(fetched.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or_default();
+    let mut head = format!("HTTP/1.1 {} {}\r\n", fetched.status, reason);
+    for (name, value) in &fetched.headers {
+        let name = name.to_lowercase();
+        if matches!(
+            name.as_str(),
+            "content-encoding" | "content-length" | "transfer-encoding"
+        ) {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("content-length: {}\r\n\r\n", fetched.body.len()));
+    let mut block = head.into_bytes();
+    block.extend_from_slice(&fetched.body);
+    block
+}
+
+fn request_block(fetched: &Fetched) -> Vec<u8> {
+    let (target, host) = match Url::parse(&fetched.url) {
+        Ok(url) => {
+            let target = match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_owned(),
+            };
+            let host = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_owned(),
+                (None, _) => String::new(),
+            };
+            (target, host)
+        }
+        Err(_) => ("/".to_owned(), String::new()),
+    };
+    let mut head = format!("GET {} HTTP/1.1\r\nhost: {}\r\n", target, host);
+    for (name, value) in &fetched.request_headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+/// A `urn:uuid` shaped id, derived from `key`.
+fn record_id(key: &str) -> String {
+    let hash = Sha256::digest(key.as_bytes());
+    let hex: String = hash[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "<urn:uuid:{}-{}-{}-{}-{}>",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn warc_date(timestamp: f64) -> String {
+    chrono::DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|datetime| datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::bufread::GzDecoder;
+
+    use super::*;
+
+    /// Splits a warc file into its records, one per gzip member.
+    fn records(mut bytes: &[u8]) -> Vec<String> {
+        let mut records = vec![];
+        while !bytes.is_empty() {
+            let mut decoder = GzDecoder::new(bytes);
+            let mut record = String::new();
+            let _read = decoder.read_to_string(&mut record).unwrap();
+            bytes = decoder.into_inner();
+            records.push(record);
+        }
+        records
+    }
+
+    /// The header fields and block of a record.
+    fn parse(record: &str) -> (Vec<(&str, &str)>, &str) {
+        let record = record.strip_prefix("WARC/1.1\r\n").unwrap();
+        let record = record.strip_suffix("\r\n\r\n").unwrap();
+        let (head, block) = record.split_once("\r\n\r\n").unwrap();
+        let fields: Vec<(&str, &str)> = head
+            .split("\r\n")
+            .map(|line| line.split_once(": ").unwrap())
+            .collect();
+        (fields, block)
+    }
+
+    fn field<'a>(fields: &[(&str, &'a str)], name: &str) -> &'a str {
+        fields
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+            .unwrap()
+    }
+
+    fn fetched() -> Fetched {
+        Fetched {
+            url: "https://example.com:8080/a?b=c".into(),
+            request_headers: vec![("user-agent".into(), "links".into())],
+            status: 200,
+            headers: vec![
+                ("Content-Type".into(), "text/html".into()),
+                ("Content-Encoding".into(), "gzip".into()),
+                ("Content-Length".into(), "12".into()),
+            ],
+            body: b"<p>hello</p>".to_vec(),
+            redirects: vec![],
+        }
+    }
+
+    fn warc() -> Vec<u8> {
+        let mut warc = Warc::default();
+        warc.warcinfo("links.warc.gz", 1700000000.0).unwrap();
+        warc.exchange("link:1700000000:0", 1700000000.0, &fetched())
+            .unwrap();
+        warc.finish()
+    }
+
+    #[test]
+    fn writes_one_gzip_member_per_record() {
+        let records = records(&warc());
+        assert_eq!(records.len(), 3);
+        for record in &records {
+            let (fields, block) = parse(record);
+            assert_eq!(field(&fields, "Content-Length"), block.len().to_string());
+            assert_eq!(field(&fields, "WARC-Date"), "2023-11-14T22:13:20Z");
+        }
+        let (info, _block) = parse(&records[0]);
+        assert_eq!(field(&info, "WARC-Type"), "warcinfo");
+        assert_eq!(field(&info, "WARC-Filename"), "links.warc.gz");
+    }
+
+    #[test]
+    fn writes_responses_and_their_requests() {
+        let records = records(&warc());
+        let (response, block) = parse(&records[1]);
+        assert_eq!(field(&response, "WARC-Type"), "response");
+        assert_eq!(
+            field(&response, "WARC-Target-URI"),
+            "https://example.com:8080/a?b=c"
+        );
+        // the body is stored decoded, so its encoding headers don't apply
+        assert_eq!(
+            block,
+            "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ncontent-length: 12\r\n\r\n<p>hello</p>"
+        );
+
+        let (request, block) = parse(&records[2]);
+        assert_eq!(field(&request, "WARC-Type"), "request");
+        assert_eq!(
+            field(&request, "WARC-Concurrent-To"),
+            field(&response, "WARC-Record-ID")
+        );
+        assert_eq!(
+            block,
+            "GET /a?b=c HTTP/1.1\r\nhost: example.com:8080\r\nuser-agent: links\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn record_ids_follow_the_exchange_key() {
+        let ids = |key: &str| -> Vec<String> {
+            let mut warc = Warc::default();
+            warc.exchange(key, 1700000000.0, &fetched()).unwrap();
+            records(&warc.finish())
+                .iter()
+                .map(|record| {
+                    let (fields, _block) = parse(record);
+                    field(&fields, "WARC-Record-ID").to_owned()
+                })
+                .collect()
+        };
+        let first = ids("link:1700000000:0");
+        assert_eq!(first, ids("link:1700000000:0"));
+        assert_ne!(first[0], first[1]);
+        // recapturing the link must not reuse the earlier capture's ids
+        let recaptured = ids("link:1700000500:0");
+        assert!(first.iter().all(|id| !recaptured.contains(id)));
+        for id in &first {
+            assert!(id.starts_with("<urn:uuid:") && id.ends_with('>'));
+            assert_eq!(id.len(), "<urn:uuid:>".len() + 36);
+        }
+    }
+}